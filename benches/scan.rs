@@ -0,0 +1,91 @@
+//! Effective bandwidth of the scan strategies, counting one read and one write per element.
+//!
+//! The time of a scan is measured on the device, between timestamps written before and after a
+//! batch of scans recorded into a single encoder. It covers the dispatches of the scans, but
+//! neither the upload of the input nor the compilation of the pipelines, nor the time spent
+//! recording and submitting the scans and allocating their intermediate buffers on the host.
+//!
+//! `cargo bench --bench scan`
+
+use std::time::Duration;
+
+use pashmina::{BufferRange, Engine, ScanDirection, ScanKind, ScanOp, ScanStrategy};
+use wgpu::util::DeviceExt;
+
+const RUNS: u32 = 20;
+
+async fn time_scan(engine: &Engine, buf: &wgpu::Buffer) -> anyhow::Result<Duration> {
+    let range = || BufferRange::new(buf, 0..buf.size() / 4);
+    let record_scan = |encoder: &mut wgpu::CommandEncoder| {
+        engine.record_scan_range::<u32>(encoder, range(), ScanOp::Add, ScanKind::Inclusive, ScanDirection::Forward)
+    };
+
+    // the first run compiles the pipelines
+    let mut encoder = engine.device.create_command_encoder(&Default::default());
+    record_scan(&mut encoder)?;
+    engine.queue.submit(Some(encoder.finish()));
+
+    let query_set = engine.device.create_query_set(&wgpu::QuerySetDescriptor {
+        label: Some("bench timestamps"),
+        ty: wgpu::QueryType::Timestamp,
+        count: 2,
+    });
+    let timestamps = engine.device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("bench timestamps"),
+        size: 2 * 8,
+        usage: wgpu::BufferUsages::QUERY_RESOLVE | wgpu::BufferUsages::COPY_SRC,
+        mapped_at_creation: false,
+    });
+    // empty passes that only write the timestamps
+    let timestamp_pass = |encoder: &mut wgpu::CommandEncoder, index| {
+        encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: None,
+            timestamp_writes: Some(wgpu::ComputePassTimestampWrites {
+                query_set: &query_set,
+                beginning_of_pass_write_index: Some(index),
+                end_of_pass_write_index: None,
+            }),
+        });
+    };
+
+    let mut encoder = engine.device.create_command_encoder(&Default::default());
+    timestamp_pass(&mut encoder, 0);
+    for _ in 0..RUNS {
+        record_scan(&mut encoder)?;
+    }
+    timestamp_pass(&mut encoder, 1);
+    encoder.resolve_query_set(&query_set, 0..2, &timestamps, 0);
+    engine.queue.submit(Some(encoder.finish()));
+
+    let timestamps: Vec<u64> = engine.map_buffer(&timestamps).await?;
+    let nanos = (timestamps[1] - timestamps[0]) as f64 * engine.queue.get_timestamp_period() as f64;
+    Ok(Duration::from_nanos(nanos as u64) / RUNS)
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let mut engine = Engine::new().await?;
+
+    println!("{: <10} | {: <24} | {: <24}", "elements", "multi-level", "single pass");
+
+    for shift in [16, 18, 20, 22, 23] {
+        let len = 1u64 << shift;
+        let buf = engine.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("bench buffer"),
+            contents: bytemuck::cast_slice(&vec![1u32; len as usize]),
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+
+        let mut cells = vec![];
+        for strategy in [ScanStrategy::MultiLevel, ScanStrategy::SinglePass] {
+            engine.scan_strategy = strategy;
+            let time = time_scan(&engine, &buf).await?;
+            let bandwidth = (2 * 4 * len) as f64 / time.as_secs_f64() / 1e9;
+            cells.push(format!("{:>9.3} ms {:>7.2} GB/s", time.as_secs_f64() * 1e3, bandwidth));
+        }
+
+        println!("{: <10} | {: <24} | {: <24}", len, cells[0], cells[1]);
+    }
+
+    Ok(())
+}
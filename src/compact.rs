@@ -0,0 +1,305 @@
+use anyhow::Context;
+use wgpu::util::DeviceExt;
+
+use crate::pipeline::{Pipeline, STORAGE, STORAGE_READ};
+use crate::{Engine, ScanKind};
+
+const COMPACT: &str = include_str!("kernels/compact.wgsl");
+
+/// Appends compact.wgsl to the `Element` and `keep()` declarations, failing if they don't parse
+/// or if the elements it reads are not `elem_size` bytes apart.
+fn compact_source(decls: &str, elem_size: u64) -> anyhow::Result<String> {
+    let source = format!("{}\n{}", decls, COMPACT);
+    let module = wgpu::naga::front::wgsl::parse_str(&source).map_err(|e| anyhow::anyhow!("{}", e.emit_to_string(&source)))?;
+    let (_, input) = module
+        .global_variables
+        .iter()
+        .find(|(_, var)| var.name.as_deref() == Some("input"))
+        .context("compact.wgsl has no input binding")?;
+    if let wgpu::naga::TypeInner::Array { stride, .. } = module.types[input.ty].inner {
+        anyhow::ensure!(
+            stride as u64 == elem_size,
+            "elements of {} bytes are read as a WGSL type of {} bytes",
+            elem_size,
+            stride,
+        );
+    }
+    Ok(source)
+}
+
+/// Which elements [`Engine::compact`] keeps.
+#[derive(Copy, Clone, Debug)]
+pub enum Predicate<'a, F> {
+    /// Keeps the elements with a nonzero `u32` flag, one per element.
+    Flags(F),
+    /// Keeps the elements for which a WGSL `bool` expression of `e` holds, with `e` read as the
+    /// WGSL type `ty`, e.g. `Predicate::Wgsl { ty: "vec3f", expr: "e.x > 0.0" }` for [`crate::Vec3A`]s.
+    /// `ty` must have the size of the elements, and a `ty` or `expr` that doesn't compile fails
+    /// the compaction.
+    Wgsl { ty: &'a str, expr: &'a str },
+}
+
+/// The result of [`Engine::compact_inner`].
+pub struct Compacted {
+    /// As large as the input, with the kept elements at the start in their original order.
+    pub output: wgpu::Buffer,
+    /// The number of kept elements, as a single `u32`.
+    pub count: wgpu::Buffer,
+}
+
+impl Engine {
+    /// Returns the elements of `input` that satisfy `predicate`, in order.
+    pub async fn compact<T: bytemuck::Pod>(&self, input: &[T], predicate: Predicate<'_, &[u32]>) -> anyhow::Result<Vec<T>> {
+        if let Predicate::Flags(flags) = predicate {
+            anyhow::ensure!(
+                flags.len() == input.len(),
+                "expected one flag per element, got {} flags for {} elements",
+                flags.len(),
+                input.len(),
+            );
+        }
+
+        if input.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let input_buf = self
+            .device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("compact input"),
+                contents: bytemuck::cast_slice(input),
+                usage: wgpu::BufferUsages::STORAGE,
+            });
+
+        let flags_buf;
+        let predicate = match predicate {
+            Predicate::Flags(flags) => {
+                flags_buf = self
+                    .device
+                    .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                        label: Some("compact flags"),
+                        contents: bytemuck::cast_slice(flags),
+                        usage: wgpu::BufferUsages::STORAGE,
+                    });
+                Predicate::Flags(&flags_buf)
+            }
+            Predicate::Wgsl { ty, expr } => Predicate::Wgsl { ty, expr },
+        };
+
+        let compacted = self.compact_inner::<T>(&input_buf, predicate)?;
+
+        let count = self.map_buffer::<u32>(&compacted.count).await?[0];
+        let mut output = self.map_buffer::<T>(&compacted.output).await?;
+        output.truncate(count as usize);
+
+        Ok(output)
+    }
+
+    /// Writes the elements of a buffer of `T`s that satisfy `predicate` to the start of a new
+    /// buffer, in order, and counts them on the device.
+    pub fn compact_inner<T: bytemuck::Pod>(
+        &self,
+        input: &wgpu::Buffer,
+        predicate: Predicate<'_, &wgpu::Buffer>,
+    ) -> anyhow::Result<Compacted> {
+        let elem_size = std::mem::size_of::<T>() as u64;
+        anyhow::ensure!(
+            elem_size > 0 && elem_size.is_multiple_of(4),
+            "element size must be a positive multiple of 4 bytes, got {}",
+            elem_size,
+        );
+
+        let len = input.size() / elem_size;
+        anyhow::ensure!(len > 0, "cannot compact an empty buffer");
+
+        let mut bindings = vec![STORAGE_READ, STORAGE, STORAGE, STORAGE];
+        let (specialization, decls) = match predicate {
+            Predicate::Flags(flags) => {
+                anyhow::ensure!(
+                    flags.size() == len * 4,
+                    "expected one flag per element, got {} bytes of flags for {} elements",
+                    flags.size(),
+                    len,
+                );
+                bindings.push(STORAGE_READ);
+
+                // the elements are only copied, as words
+                let words = elem_size / 4;
+                let decls = format!(
+                    "alias Element = array<u32, {}>;\n@group(0) @binding(4) var<storage, read> flags: array<u32>;\nfn keep(idx: u32) -> bool {{ return flags[idx] != 0u; }}\n",
+                    words,
+                );
+                (format!("flags, {} words", words), decls)
+            }
+            Predicate::Wgsl { ty, expr } => {
+                let decls = format!(
+                    "alias Element = {};\nfn keep(idx: u32) -> bool {{ let e = input[idx]; return {}; }}\n",
+                    ty, expr,
+                );
+                (format!("{}, {}, {} bytes", ty, expr, elem_size), decls)
+            }
+        };
+
+        let pipeline = |entry_point| {
+            self.checked_specialized_pipeline(
+                "compact",
+                &specialization,
+                || compact_source(&decls, elem_size),
+                entry_point,
+                &bindings,
+            )
+        };
+        let mark = pipeline("mark")?;
+        let scatter = pipeline("scatter")?;
+
+        let offsets = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("compact offsets"),
+            size: len * 4,
+            usage: wgpu::BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+        let output = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("compact output"),
+            size: input.size(),
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+        let count = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("compact count"),
+            size: 4,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+
+        let mut bufs = vec![input, &offsets, &output, &count];
+        if let Predicate::Flags(flags) = predicate {
+            bufs.push(flags);
+        }
+
+        self.dispatch_compact_kernel(&bufs, &mark, "mark", len);
+        self.prefix_sum_inner::<u32>(&offsets, ScanKind::Exclusive);
+        self.dispatch_compact_kernel(&bufs, &scatter, "scatter", len);
+
+        Ok(Compacted { output, count })
+    }
+
+    fn dispatch_compact_kernel(&self, bufs: &[&wgpu::Buffer], pipeline: &Pipeline, entry_point: &str, len: u64) {
+        const WG_SIZE: u64 = 256;
+        const MAX_WORKGROUPS: u64 = 65535;
+
+        let bind_group = self.bind_buffers(pipeline, bufs);
+
+        let mut encoder = self.device.create_command_encoder(&Default::default());
+        let workgroups = len.div_ceil(WG_SIZE);
+        {
+            let mut cpass = encoder.begin_compute_pass(&Default::default());
+            cpass.insert_debug_marker(&format!("compact {} dispatch", entry_point));
+            cpass.set_pipeline(&pipeline.pipeline);
+            cpass.set_bind_group(0, &bind_group, &[]);
+            cpass.dispatch_workgroups(
+                workgroups.min(MAX_WORKGROUPS) as u32,
+                workgroups.div_ceil(MAX_WORKGROUPS) as u32,
+                1,
+            );
+        }
+
+        self.queue.submit(Some(encoder.finish()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::assert_slices_eq;
+    use crate::Vec3A;
+
+    use rand::Rng;
+    use rand_xoshiro::{rand_core::SeedableRng, Xoshiro256PlusPlus};
+
+    #[tokio::test]
+    async fn compact_with_flags_works() -> anyhow::Result<()> {
+        let engine = Engine::new().await?;
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(0);
+
+        for len in [1, 255, 2049, 100_000, 1 << 20] {
+            // the values are their own indices, so the order is checked as well
+            let input: Vec<u32> = (0..len).collect();
+            for ratio in [0, 1, 2, 10] {
+                let flags: Vec<u32> = (0..len).map(|_| (ratio != 0 && rng.gen_ratio(1, ratio)) as u32).collect();
+                let expected: Vec<u32> = input.iter().copied().filter(|&i| flags[i as usize] != 0).collect();
+
+                let result = engine.compact(&input, Predicate::Flags(&flags)).await?;
+                assert_slices_eq(&result, &expected);
+            }
+        }
+
+        // elements of several words
+        let input: Vec<[u32; 3]> = (0..10_000).map(|i| [i, i * 2, i * 3]).collect();
+        let flags: Vec<u32> = (0..10_000).map(|i| (i % 3 == 0) as u32).collect();
+        let expected: Vec<[u32; 3]> = input.iter().copied().filter(|e| e[0] % 3 == 0).collect();
+        assert_slices_eq(&engine.compact(&input, Predicate::Flags(&flags)).await?, &expected);
+
+        assert!(engine.compact(&input, Predicate::Flags(&flags[1..])).await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn compact_with_wgsl_predicate_works() -> anyhow::Result<()> {
+        let engine = Engine::new().await?;
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(1);
+
+        let input: Vec<Vec3A> = (0..100_000)
+            .map(|_| Vec3A::new(rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0)))
+            .collect();
+        let expected: Vec<Vec3A> = input.iter().copied().filter(|p| p.x > 0.5 && p.z < 0.0).collect();
+
+        let predicate = Predicate::Wgsl { ty: "vec3f", expr: "e.x > 0.5 && e.z < 0.0" };
+        assert_slices_eq(&engine.compact(&input, predicate).await?, &expected);
+
+        // the count stays on the device
+        let buf = engine.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: None,
+            contents: bytemuck::cast_slice(&input),
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+        let predicate = Predicate::Wgsl { ty: "vec3f", expr: "e.x > 0.5 && e.z < 0.0" };
+        let compacted = engine.compact_inner::<Vec3A>(&buf, predicate)?;
+        assert_eq!(engine.map_buffer::<u32>(&compacted.count).await?, [expected.len() as u32]);
+
+        let input: Vec<i32> = (-5000..5000).collect();
+        let predicate = Predicate::Wgsl { ty: "i32", expr: "e > -1000 && (e & 7) == 0" };
+        let expected: Vec<i32> = input.iter().copied().filter(|e| *e > -1000 && e & 7 == 0).collect();
+        assert_slices_eq(&engine.compact(&input, predicate).await?, &expected);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn compact_rejects_invalid_wgsl_predicates() -> anyhow::Result<()> {
+        let engine = Engine::new().await?;
+        let input: Vec<Vec3A> = (0..1000).map(|i| Vec3A::new(i as f32, 0.0, 0.0)).collect();
+
+        let predicates = [
+            // doesn't parse
+            Predicate::Wgsl { ty: "vec3f", expr: "e.x >" },
+            Predicate::Wgsl { ty: "vec3<", expr: "true" },
+            // not a bool
+            Predicate::Wgsl { ty: "vec3f", expr: "e.x" },
+            Predicate::Wgsl { ty: "vec3f", expr: "e.w > 0.0" },
+            // 12 and 4 bytes apart instead of 16
+            Predicate::Wgsl { ty: "array<f32, 3>", expr: "e[0] > 0.0" },
+            Predicate::Wgsl { ty: "f32", expr: "e > 0.0" },
+        ];
+        for predicate in predicates {
+            assert!(engine.compact(&input, predicate).await.is_err(), "{:?} was accepted", predicate);
+            // nothing invalid is left in the cache either
+            assert!(engine.compact(&input, predicate).await.is_err(), "{:?} was accepted", predicate);
+        }
+
+        let predicate = Predicate::Wgsl { ty: "vec4f", expr: "e.x >= 500.0" };
+        assert_slices_eq(&engine.compact(&input, predicate).await?, &input[500..]);
+
+        Ok(())
+    }
+}
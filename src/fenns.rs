@@ -1,228 +1,181 @@
+use crate::pipeline::{Pipeline, STORAGE, STORAGE_READ, UNIFORM};
 use crate::Engine;
 
-impl Engine {
-    const FENNS_WG_SIZE: u64 = 64;
+/// What the FENNS kernels do with particles outside of the grid.
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OutOfBounds {
+    /// Sort them into the closest edge cell.
+    Clamp = 0,
+    /// Leave them out of the grid, packed at the end of the sorted particles.
+    Drop = 1,
+    /// Same as `Drop` on the GPU, but `NeighborSearch` reports them as an error.
+    Error = 2,
+}
 
-    pub fn fenns_sort1(&self, bufs: &[&wgpu::Buffer]) {
-        let bind_group_layout =
-            self.device
-                .create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                    label: None,
-                    entries: &[
-                        wgpu::BindGroupLayoutEntry {
-                            binding: 0,
-                            visibility: wgpu::ShaderStages::COMPUTE,
-                            ty: wgpu::BindingType::Buffer {
-                                ty: wgpu::BufferBindingType::Uniform,
-                                has_dynamic_offset: false,
-                                min_binding_size: None,
-                            },
-                            count: None,
-                        },
-                        wgpu::BindGroupLayoutEntry {
-                            binding: 1,
-                            visibility: wgpu::ShaderStages::COMPUTE,
-                            ty: wgpu::BindingType::Buffer {
-                                ty: wgpu::BufferBindingType::Storage { read_only: true },
-                                has_dynamic_offset: false,
-                                min_binding_size: None,
-                            },
-                            count: None,
-                        },
-                        wgpu::BindGroupLayoutEntry {
-                            binding: 2,
-                            visibility: wgpu::ShaderStages::COMPUTE,
-                            ty: wgpu::BindingType::Buffer {
-                                ty: wgpu::BufferBindingType::Storage { read_only: false },
-                                has_dynamic_offset: false,
-                                min_binding_size: None,
-                            },
-                            count: None,
-                        },
-                    ],
-                });
-
-        let pipeline_layout = self
-            .device
-            .create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-                label: None,
-                bind_group_layouts: &[&bind_group_layout],
-                push_constant_ranges: &[],
-            });
+/// Uniform shared by the FENNS kernels, mirrors `Params` in `kernels/fenns_*.wgsl`.
+///
+/// The grid spans `grid_dim * cell_width` from `origin`. The count buffer passed to the kernels
+/// must be exactly [`FennsParams::count_buffer_size`] bytes long.
+///
+/// Along periodic axes positions wrap around the grid, so no particle is ever out of bounds
+/// there, and distances follow the minimum-image convention. The search radius must then be at
+/// most half the box length.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct FennsParams {
+    pub origin: [f32; 3],
+    pub cell_width: f32,
+    pub grid_dim: [u32; 3],
+    pub search_radius: f32,
+    out_of_bounds: u32,
+    periodic: u32,
+    table_size: u32,
+    _padding: u32,
+}
 
-        let pipeline = self
-            .device
-            .create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
-                label: None,
-                layout: Some(&pipeline_layout),
-                module: self.kernels.get("fenns_sort1").unwrap(),
-                entry_point: "main",
-            });
+impl FennsParams {
+    pub fn new(grid_dim: [u32; 3], cell_width: f32, search_radius: f32) -> Self {
+        Self {
+            origin: [0.0; 3],
+            cell_width,
+            grid_dim,
+            search_radius,
+            out_of_bounds: OutOfBounds::Clamp as u32,
+            periodic: 0,
+            table_size: 0,
+            _padding: 0,
+        }
+    }
 
-        let bind_group = self.device.create_bind_group(&wgpu::BindGroupDescriptor {
-            label: None,
-            layout: &bind_group_layout,
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: bufs[0].as_entire_binding(),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 1,
-                    resource: bufs[1].as_entire_binding(),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 2,
-                    resource: bufs[2].as_entire_binding(),
-                },
-            ],
-        });
+    pub fn with_origin(mut self, origin: [f32; 3]) -> Self {
+        self.origin = origin;
+        self
+    }
 
-        let mut encoder = self.device.create_command_encoder(&Default::default());
-        let len = bufs[1].size() / 16;
+    pub fn with_out_of_bounds(mut self, out_of_bounds: OutOfBounds) -> Self {
+        self.out_of_bounds = out_of_bounds as u32;
+        self
+    }
 
-        {
-            let mut cpass = encoder.begin_compute_pass(&Default::default());
-            cpass.insert_debug_marker("fenns_sort1 dispatch");
-            cpass.set_pipeline(&pipeline);
-            cpass.set_bind_group(0, &bind_group, &[]);
-            cpass.dispatch_workgroups(len.div_ceil(Self::FENNS_WG_SIZE) as u32, 1, 1);
+    pub fn with_periodic(mut self, periodic: [bool; 3]) -> Self {
+        self.periodic = periodic
+            .iter()
+            .enumerate()
+            .map(|(axis, &p)| (p as u32) << axis)
+            .sum();
+        self
+    }
+
+    /// Counts the particles in `table_size` slots indexed by a hash of their cell coordinates,
+    /// instead of in a dense grid. Every position then lies in a cell, `grid_dim` and
+    /// the out of bounds policy are ignored.
+    pub fn with_hash_table(mut self, table_size: u32) -> Self {
+        self.table_size = table_size;
+        self
+    }
+
+    /// Number of cells, or of hash table slots.
+    pub fn grid_size(&self) -> u64 {
+        if self.table_size != 0 {
+            return self.table_size as u64;
         }
+        self.grid_dim.iter().map(|&dim| dim as u64).product()
+    }
 
-        self.queue.submit(Some(encoder.finish()));
+    /// Border and interior particle counts of each cell, interleaved. An exclusive scan of the
+    /// counts filled in by [`Engine::fenns_sort1`] yields the start of each cell's border and
+    /// interior section, which [`Engine::fenns_sort2`] advances to their ends.
+    pub fn count_buffer_size(&self) -> u64 {
+        4 * 2 * self.grid_size()
     }
+}
 
-    pub fn fenns_sort_shift(&self, buf: &wgpu::Buffer) {
-        let pipeline = self
-            .device
-            .create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
-                label: None,
-                layout: None,
-                module: self.kernels.get("fenns_sort_shift").unwrap(),
-                entry_point: "main",
-            });
+impl Engine {
+    const FENNS_WG_SIZE: u64 = 64;
 
-        let bind_group_layout = pipeline.get_bind_group_layout(0);
-        let bind_group = self.device.create_bind_group(&wgpu::BindGroupDescriptor {
-            label: None,
-            layout: &bind_group_layout,
-            entries: &[wgpu::BindGroupEntry {
-                binding: 0,
-                resource: buf.as_entire_binding(),
-            }],
-        });
+    /// Counts the border and interior particles of each cell, in workgroup memory if the grid
+    /// fits there.
+    ///
+    /// The stray buffer holds two u32s and must be zeroed along with the count buffer.
+    pub fn fenns_sort1(&self, bufs: &[&wgpu::Buffer]) {
+        let grid_size = bufs[2].size() / 8;
+        let entry_point = if grid_size <= self.capabilities.fenns_shared_grid_size {
+            "main"
+        } else {
+            "main_global"
+        };
+
+        let pipeline = self.pipeline("fenns_sort1", entry_point, &[UNIFORM, STORAGE_READ, STORAGE, STORAGE]);
+        self.dispatch_fenns_kernel(&pipeline, bufs, "fenns_sort1");
+    }
+
+    /// Scatters the particles into their cells, the border particles of each cell first. The
+    /// counts must have gone through an exclusive scan since [`Engine::fenns_sort1`].
+    ///
+    /// Besides the reordered particles, writes the original index of each reordered particle to
+    /// `bufs[5]` and the reordered index of each original particle to `bufs[6]`.
+    pub fn fenns_sort2(&self, bufs: &[&wgpu::Buffer]) {
+        let pipeline = self.pipeline(
+            "fenns_sort2",
+            "main",
+            &[UNIFORM, STORAGE_READ, STORAGE, STORAGE, STORAGE, STORAGE, STORAGE],
+        );
+        self.dispatch_fenns_kernel(&pipeline, bufs, "fenns_sort2");
+    }
+
+    /// Reorders a per-particle attribute buffer with the order written by [`Engine::fenns_sort2`].
+    ///
+    /// `bufs` are the order, the attributes and their reordered copy. Attributes are gathered as
+    /// u32 words, so their size must be a multiple of 4 bytes.
+    pub fn fenns_gather(&self, bufs: &[&wgpu::Buffer]) {
+        const WG_SIZE: u64 = 256;
+        const MAX_WORKGROUPS: u64 = 65535;
+
+        let pipeline = self.pipeline("fenns_gather", "main", &[STORAGE_READ, STORAGE_READ, STORAGE]);
+        let bind_group = self.bind_buffers(&pipeline, bufs);
 
         let mut encoder = self.device.create_command_encoder(&Default::default());
-        let len = buf.size() / 4;
+        let workgroups = (bufs[2].size() / 4).div_ceil(WG_SIZE);
         {
             let mut cpass = encoder.begin_compute_pass(&Default::default());
-            cpass.insert_debug_marker("fenns_sort_shuffle dispatch");
-            cpass.set_pipeline(&pipeline);
+            cpass.insert_debug_marker("fenns_gather dispatch");
+            cpass.set_pipeline(&pipeline.pipeline);
             cpass.set_bind_group(0, &bind_group, &[]);
-            cpass.dispatch_workgroups(len.div_ceil(Self::FENNS_WG_SIZE) as u32, 1, 1);
+            cpass.dispatch_workgroups(
+                workgroups.min(MAX_WORKGROUPS) as u32,
+                workgroups.div_ceil(MAX_WORKGROUPS) as u32,
+                1,
+            );
         }
 
         self.queue.submit(Some(encoder.finish()));
     }
 
-    pub fn fenns_sort2(&self, bufs: &[&wgpu::Buffer]) {
-        let bind_group_layout =
-            self.device
-                .create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                    label: None,
-                    entries: &[
-                        wgpu::BindGroupLayoutEntry {
-                            binding: 0,
-                            visibility: wgpu::ShaderStages::COMPUTE,
-                            ty: wgpu::BindingType::Buffer {
-                                ty: wgpu::BufferBindingType::Uniform,
-                                has_dynamic_offset: false,
-                                min_binding_size: None,
-                            },
-                            count: None,
-                        },
-                        wgpu::BindGroupLayoutEntry {
-                            binding: 1,
-                            visibility: wgpu::ShaderStages::COMPUTE,
-                            ty: wgpu::BindingType::Buffer {
-                                ty: wgpu::BufferBindingType::Storage { read_only: true },
-                                has_dynamic_offset: false,
-                                min_binding_size: None,
-                            },
-                            count: None,
-                        },
-                        wgpu::BindGroupLayoutEntry {
-                            binding: 2,
-                            visibility: wgpu::ShaderStages::COMPUTE,
-                            ty: wgpu::BindingType::Buffer {
-                                ty: wgpu::BufferBindingType::Storage { read_only: false },
-                                has_dynamic_offset: false,
-                                min_binding_size: None,
-                            },
-                            count: None,
-                        },
-                        wgpu::BindGroupLayoutEntry {
-                            binding: 3,
-                            visibility: wgpu::ShaderStages::COMPUTE,
-                            ty: wgpu::BindingType::Buffer {
-                                ty: wgpu::BufferBindingType::Storage { read_only: false },
-                                has_dynamic_offset: false,
-                                min_binding_size: None,
-                            },
-                            count: None,
-                        },
-                    ],
-                });
-
-        let pipeline_layout = self
-            .device
-            .create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-                label: None,
-                bind_group_layouts: &[&bind_group_layout],
-                push_constant_ranges: &[],
-            });
+    pub fn fenns_search(&self, bufs: &[&wgpu::Buffer]) {
+        let pipeline = self.pipeline("fenns_search", "main", &[UNIFORM, STORAGE_READ, STORAGE_READ, STORAGE, STORAGE]);
+        self.dispatch_fenns_kernel(&pipeline, bufs, "fenns_search");
+    }
 
-        let pipeline = self
-            .device
-            .create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
-                label: None,
-                layout: Some(&pipeline_layout),
-                module: self.kernels.get("fenns_sort2").unwrap(),
-                entry_point: "main",
-            });
+    /// Runs a thread per particle of `bufs[1]`, splitting the dispatch along y when it has too
+    /// many workgroups, see particle_idx() in fenns_grid.wgsl.
+    fn dispatch_fenns_kernel(&self, pipeline: &Pipeline, bufs: &[&wgpu::Buffer], kernel: &str) {
+        const MAX_WORKGROUPS: u64 = 65535;
 
-        let bind_group = self.device.create_bind_group(&wgpu::BindGroupDescriptor {
-            label: None,
-            layout: &bind_group_layout,
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: bufs[0].as_entire_binding(),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 1,
-                    resource: bufs[1].as_entire_binding(),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 2,
-                    resource: bufs[2].as_entire_binding(),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 3,
-                    resource: bufs[3].as_entire_binding(),
-                },
-            ],
-        });
+        let bind_group = self.bind_buffers(pipeline, bufs);
+        let workgroups = (bufs[1].size() / 16).div_ceil(Self::FENNS_WG_SIZE);
 
         let mut encoder = self.device.create_command_encoder(&Default::default());
-        let len = bufs[1].size() / 16;
-
         {
             let mut cpass = encoder.begin_compute_pass(&Default::default());
-            cpass.insert_debug_marker("fenns_sort2 dispatch");
-            cpass.set_pipeline(&pipeline);
+            cpass.insert_debug_marker(&format!("{} dispatch", kernel));
+            cpass.set_pipeline(&pipeline.pipeline);
             cpass.set_bind_group(0, &bind_group, &[]);
-            cpass.dispatch_workgroups(len.div_ceil(Self::FENNS_WG_SIZE) as u32, 1, 1);
+            cpass.dispatch_workgroups(
+                workgroups.min(MAX_WORKGROUPS) as u32,
+                workgroups.div_ceil(MAX_WORKGROUPS) as u32,
+                1,
+            );
         }
 
         self.queue.submit(Some(encoder.finish()));
@@ -230,10 +183,10 @@ impl Engine {
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
     use crate::tests::{assert_slices_eq, print_slice_comparison};
-    use crate::Vec3A;
+    use crate::{BufferRange, ScanKind, Vec3A};
 
     use std::iter::zip;
 
@@ -241,24 +194,24 @@ mod tests {
     use rand_xoshiro::{rand_core::SeedableRng, Xoshiro256PlusPlus};
     use wgpu::util::DeviceExt;
 
-    pub fn gen_particles(seed: u64, grid_dim: usize) -> (Vec<Vec3A>, Vec<u32>) {
+    pub fn gen_particles(seed: u64, grid_dim: [usize; 3]) -> (Vec<Vec3A>, Vec<u32>) {
         let mut rng = Xoshiro256PlusPlus::seed_from_u64(seed);
 
-        let grid_size: usize = grid_dim * grid_dim * grid_dim;
+        let grid_size: usize = grid_dim.iter().product();
         let particle_counts: Vec<u32> = (0..grid_size).map(|_| rng.gen_range(1..=10)).collect();
 
         let mut particles: Vec<Vec3A> = vec![];
 
         for (i, n) in zip(0..grid_size, &particle_counts) {
-            let x = i % 18;
-            let y = (i / 18) % 18;
-            let z = i / (18 * 18);
+            let x = i % grid_dim[0];
+            let y = (i / grid_dim[0]) % grid_dim[1];
+            let z = i / (grid_dim[0] * grid_dim[1]);
 
             for _ in 0..*n {
                 particles.push(Vec3A::new(
-                    x as f32 + rng.gen::<f32>(),
-                    y as f32 + rng.gen::<f32>(),
-                    z as f32 + rng.gen::<f32>(),
+                    gen_in_cell(&mut rng, x),
+                    gen_in_cell(&mut rng, y),
+                    gen_in_cell(&mut rng, z),
                 ));
             }
         }
@@ -266,13 +219,23 @@ mod tests {
         (particles, particle_counts)
     }
 
+    // `cell + rng.gen::<f32>()` can round up into the next cell
+    fn gen_in_cell(rng: &mut Xoshiro256PlusPlus, cell: usize) -> f32 {
+        loop {
+            let coord = cell as f32 + rng.gen::<f32>();
+            if coord < (cell + 1) as f32 {
+                return coord;
+            }
+        }
+    }
+
     #[tokio::test]
     async fn check_fenns_sort1() -> anyhow::Result<()> {
         let engine = Engine::new().await?;
 
         const SEED: u64 = 0;
-        const GRID_DIM: usize = 18;
-        const GRID_SIZE: usize = GRID_DIM * GRID_DIM * GRID_DIM;
+        const GRID_DIM: [usize; 3] = [16, 16, 12];
+        const GRID_SIZE: usize = GRID_DIM[0] * GRID_DIM[1] * GRID_DIM[2];
         let (particles, particle_counts) = gen_particles(SEED, GRID_DIM);
 
         println!("Particle count: {}", particles.len());
@@ -282,7 +245,7 @@ mod tests {
             .device
             .create_buffer_init(&wgpu::util::BufferInitDescriptor {
                 label: Some("fenns_sort1/buf0"),
-                contents: bytemuck::cast_slice(&[1f32, 0.1f32]),
+                contents: bytemuck::bytes_of(&FennsParams::new(GRID_DIM.map(|dim| dim as u32), 1.0, 0.1)),
                 usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
             });
 
@@ -296,15 +259,153 @@ mod tests {
 
         let count_buf = engine.device.create_buffer(&wgpu::BufferDescriptor {
             label: Some("fenns_sort1/buf2"),
-            size: 4 * GRID_SIZE as u64 * 2,
+            size: 4 * 2 * GRID_SIZE as u64,
             mapped_at_creation: false,
             usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
         });
 
-        engine.fenns_sort1(&[&params_buf, &particles_buf, &count_buf]);
-        let result = engine.map_buffer(&count_buf).await?;
+        let strays_buf = engine.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("fenns_sort1/strays"),
+            size: 4 * 2,
+            mapped_at_creation: false,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+        });
+
+        engine.fenns_sort1(&[&params_buf, &particles_buf, &count_buf, &strays_buf]);
+        let result: Vec<u32> = engine.map_buffer(&count_buf).await?;
 
-        assert_slices_eq(&result[0..GRID_SIZE], &particle_counts);
+        let cell_counts: Vec<u32> = result.chunks(2).map(|counts| counts[0] + counts[1]).collect();
+        assert_slices_eq(&cell_counts, &particle_counts);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn cell_counts_sum_into_box_counts() -> anyhow::Result<()> {
+        let engine = Engine::new().await?;
+
+        const SEED: u64 = 3;
+        const GRID_DIM: [usize; 3] = [16, 10, 12];
+        let (particles, particle_counts) = gen_particles(SEED, GRID_DIM);
+        let params = FennsParams::new(GRID_DIM.map(|dim| dim as u32), 1.0, 0.1);
+
+        let params_buf = engine
+            .device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("fenns_sort1/buf0"),
+                contents: bytemuck::bytes_of(&params),
+                usage: wgpu::BufferUsages::UNIFORM,
+            });
+
+        let particles_buf = engine
+            .device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("fenns_sort1/buf1"),
+                contents: bytemuck::cast_slice(&particles),
+                usage: wgpu::BufferUsages::STORAGE,
+            });
+
+        let count_buf = engine.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("fenns_sort1/buf2"),
+            size: params.count_buffer_size(),
+            mapped_at_creation: false,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+        });
+
+        let strays_buf = engine.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("fenns_sort1/strays"),
+            size: 4 * 2,
+            mapped_at_creation: false,
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+
+        engine.fenns_sort1(&[&params_buf, &particles_buf, &count_buf, &strays_buf]);
+
+        // the border and interior counts of a cell are neighbors along x
+        let dims = [2 * GRID_DIM[0] as u32, GRID_DIM[1] as u32, GRID_DIM[2] as u32];
+        engine.summed_area_table_inner::<u32>(BufferRange::new(&count_buf, 0..2 * params.grid_size()), &dims)?;
+        let table: Vec<u32> = engine.map_buffer(&count_buf).await?;
+
+        // the particles in the cells up to and including [x, y, z]
+        let at = |x: usize, y: usize, z: usize| table[(z * GRID_DIM[1] + y) * 2 * GRID_DIM[0] + 2 * x + 1];
+        let (lower, upper) = ([2, 0, 5], [13, 8, 11]);
+        let box_count = at(upper[0], upper[1], upper[2]) + at(lower[0], lower[1], upper[2]) + at(lower[0], upper[1], lower[2])
+            + at(upper[0], lower[1], lower[2])
+            - at(lower[0], upper[1], upper[2])
+            - at(upper[0], lower[1], upper[2])
+            - at(upper[0], upper[1], lower[2])
+            - at(lower[0], lower[1], lower[2]);
+        let mut expected = 0;
+        for z in lower[2] + 1..=upper[2] {
+            for y in lower[1] + 1..=upper[1] {
+                for x in lower[0] + 1..=upper[0] {
+                    expected += particle_counts[(z * GRID_DIM[1] + y) * GRID_DIM[0] + x];
+                }
+            }
+        }
+        assert_eq!(box_count, expected);
+        assert_eq!(at(GRID_DIM[0] - 1, GRID_DIM[1] - 1, GRID_DIM[2] - 1), particles.len() as u32);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn check_fenns_sort1_large_grid() -> anyhow::Result<()> {
+        let engine = Engine::new().await?;
+
+        const GRID_DIM: [usize; 3] = [160, 120, 200];
+        const GRID_SIZE: usize = GRID_DIM[0] * GRID_DIM[1] * GRID_DIM[2];
+        assert!(GRID_SIZE as u64 > engine.capabilities.fenns_shared_grid_size);
+
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(0);
+        let particles: Vec<Vec3A> = (0..200_000)
+            .map(|_| {
+                let [x, y, z] = GRID_DIM.map(|dim| rng.gen_range(0..dim));
+                Vec3A::new(gen_in_cell(&mut rng, x), gen_in_cell(&mut rng, y), gen_in_cell(&mut rng, z))
+            })
+            .collect();
+
+        let mut particle_counts = vec![0u32; GRID_SIZE];
+        for particle in &particles {
+            let (x, y, z) = (particle.x as usize, particle.y as usize, particle.z as usize);
+            particle_counts[z * GRID_DIM[1] * GRID_DIM[0] + y * GRID_DIM[0] + x] += 1;
+        }
+
+        let params_buf = engine
+            .device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("fenns_sort1/buf0"),
+                contents: bytemuck::bytes_of(&FennsParams::new(GRID_DIM.map(|dim| dim as u32), 1.0, 0.1)),
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            });
+
+        let particles_buf = engine
+            .device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("fenns_sort1/buf1"),
+                contents: bytemuck::cast_slice(&particles),
+                usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+            });
+
+        let count_buf = engine.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("fenns_sort1/buf2"),
+            size: 4 * 2 * GRID_SIZE as u64,
+            mapped_at_creation: false,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+        });
+
+        let strays_buf = engine.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("fenns_sort1/strays"),
+            size: 4 * 2,
+            mapped_at_creation: false,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+        });
+
+        engine.fenns_sort1(&[&params_buf, &particles_buf, &count_buf, &strays_buf]);
+        let result: Vec<u32> = engine.map_buffer(&count_buf).await?;
+
+        let cell_counts: Vec<u32> = result.chunks(2).map(|counts| counts[0] + counts[1]).collect();
+        assert_slices_eq(&cell_counts, &particle_counts);
 
         Ok(())
     }
@@ -322,8 +423,8 @@ mod tests {
     }
 
     async fn check_fenns_sort2_inner(engine: &Engine, seed: u64) -> anyhow::Result<()> {
-        const GRID_DIM: usize = 18;
-        const GRID_SIZE: usize = GRID_DIM * GRID_DIM * GRID_DIM;
+        const GRID_DIM: [usize; 3] = [12, 24, 20];
+        const GRID_SIZE: usize = GRID_DIM[0] * GRID_DIM[1] * GRID_DIM[2];
         const CELL_SIZE: f32 = 1.0;
         const SEARCH_RADIUS: f32 = 0.1;
         let (particles, particle_counts) = gen_particles(seed, GRID_DIM);
@@ -335,7 +436,7 @@ mod tests {
             .device
             .create_buffer_init(&wgpu::util::BufferInitDescriptor {
                 label: Some("fenns_sort2/buf0"),
-                contents: bytemuck::cast_slice(&[CELL_SIZE, SEARCH_RADIUS]),
+                contents: bytemuck::bytes_of(&FennsParams::new(GRID_DIM.map(|dim| dim as u32), CELL_SIZE, SEARCH_RADIUS)),
                 usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
             });
 
@@ -349,7 +450,14 @@ mod tests {
 
         let count_buf = engine.device.create_buffer(&wgpu::BufferDescriptor {
             label: Some("fenns_sort2/buf2"),
-            size: 4 * GRID_SIZE as u64 * 2,
+            size: 4 * 2 * GRID_SIZE as u64,
+            mapped_at_creation: false,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+        });
+
+        let strays_buf = engine.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("fenns_sort2/strays"),
+            size: 4 * 2,
             mapped_at_creation: false,
             usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
         });
@@ -361,68 +469,90 @@ mod tests {
             usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
         });
 
-        engine.fenns_sort1(&[&params_buf, &particles_buf, &count_buf]);
+        let order_buf = engine.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("fenns_sort2/order"),
+            size: particles.len() as u64 * 4,
+            mapped_at_creation: false,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+        });
+
+        let inverse_order_buf = engine.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("fenns_sort2/inverse_order"),
+            size: particles.len() as u64 * 4,
+            mapped_at_creation: false,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+        });
+
+        engine.fenns_sort1(&[&params_buf, &particles_buf, &count_buf, &strays_buf]);
 
         let counts: Vec<u32> = engine.map_buffer(&count_buf).await?;
 
-        engine.prefix_sum_inner(&count_buf);
+        engine.prefix_sum_inner::<u32>(&count_buf, ScanKind::Exclusive);
 
         let summed: Vec<u32> = engine.map_buffer(&count_buf).await?;
-        let expected_sum = crate::prefix_sum::tests::prefix_sum_cpu(&counts);
+        let expected_sum = crate::prefix_sum::tests::exclusive_prefix_sum_cpu(&counts);
 
         assert_slices_eq(&summed, &expected_sum);
 
-        engine.fenns_sort_shift(&count_buf);
-        
-        let shifted: Vec<u32> = engine.map_buffer(&count_buf).await?;
-        assert_eq!(shifted[particle_counts.len()], 0);
-        assert_slices_eq(&shifted[..particle_counts.len()-1], &shifted[particle_counts.len()+1..]);
-
-        engine.fenns_sort2(&[&params_buf, &particles_buf, &count_buf, &reordered_buf]);
+        engine.fenns_sort2(&[
+            &params_buf,
+            &particles_buf,
+            &count_buf,
+            &reordered_buf,
+            &strays_buf,
+            &order_buf,
+            &inverse_order_buf,
+        ]);
 
         let reordered: Vec<Vec3A> = engine.map_buffer(&reordered_buf).await?;
-        
+        let sorted_counts: Vec<u32> = engine.map_buffer(&count_buf).await?;
+        let order: Vec<u32> = engine.map_buffer(&order_buf).await?;
+        let inverse_order: Vec<u32> = engine.map_buffer(&inverse_order_buf).await?;
+
+        for (i, &original) in order.iter().enumerate() {
+            assert_eq!(reordered[i], particles[original as usize], "particle #{} is not original #{}", i, original);
+            assert_eq!(inverse_order[original as usize], i as u32, "inverse order of #{}", original);
+        }
+
         let original_zeros = particles.iter().filter(|&&v| v == Vec3A::new(0.0,0.0,0.0)).count();
-        let reordered_zeros = reordered.iter().enumerate().filter(|(i, &v)| v == Vec3A::new(0.0,0.0,0.0));
-        
+        let reordered_zeros = reordered.iter().enumerate().filter(|(_, &v)| v == Vec3A::new(0.0,0.0,0.0));
+
         if reordered_zeros.clone().count() != original_zeros {
             panic!("Reordering has zero particles: {:?}", reordered_zeros.collect::<Vec<(usize, _)>>())
         }
 
+        // each cursor ends up at the start of the next section
+        assert_slices_eq(&sorted_counts[..2 * GRID_SIZE - 1], &summed[1..]);
+        assert_eq!(sorted_counts[2 * GRID_SIZE - 1], particles.len() as u32);
+
         let is_border_particle = |particle: Vec3A| {
-            for coord in &[particle.x, particle.y, particle.z]{
-                let cell_coord = (coord / CELL_SIZE).fract();
-                if cell_coord < SEARCH_RADIUS || cell_coord > CELL_SIZE - SEARCH_RADIUS {
-                    return true;
-                }
-            }
-            return false;      
+            [particle.x, particle.y, particle.z].iter().any(|coord| {
+                let cell_coord = (coord / CELL_SIZE).fract() * CELL_SIZE;
+                !(SEARCH_RADIUS / 2.0..=CELL_SIZE - SEARCH_RADIUS / 2.0).contains(&cell_coord)
+            })
         };
+
         let mut i = 0;
-        for count in particle_counts.into_iter() {
-            let mut border_j = 0;
-            let mut nonborder_j = 0;
+        for (cell_idx, count) in particle_counts.into_iter().enumerate() {
+            let split = sorted_counts[2 * cell_idx] as usize;
 
             for j in 0..(count as usize) {
-                if !particles[i..i+(count as usize)].iter().find(|&&x| reordered[i+j] == x).is_some() {
+                if !particles[i..i+(count as usize)].iter().any(|&x| reordered[i+j] == x) {
                     println!("Test error: expected to find particle in the same grid cell after reorder");
                     println!();
                     println!("Particle #{} (after reorder) is {:?}", i+j, reordered[i+j]);
                     println!("Should have been one of those (before reorder) at grid cell idx {}:", i);
-                    for candidate_i in i..i+(count as usize) {
-                        println!("  #{}: {:?}", candidate_i, particles[candidate_i]);
+                    for (candidate_i, candidate) in particles.iter().enumerate().skip(i).take(count as usize) {
+                        println!("  #{}: {:?}", candidate_i, candidate);
                     }
                     println!();
                     print_slice_comparison(i, "particles before", &particles, "after reorder", &reordered);
                     panic!();
                 };
 
-                let particle = particles[i+j];
-                // TODO: check if reorder idxs are correct
-                if is_border_particle(particle) {
-                    border_j += 1;
-                } else {
-                    nonborder_j += 1;
+                if is_border_particle(reordered[i+j]) != (i + j < split) {
+                    print_slice_comparison(i + j, "particles before", &particles, "after reorder", &reordered);
+                    panic!("Particle #{} is on the wrong side of the border/interior split at {}", i + j, split);
                 }
             }
             i += count as usize;
@@ -430,4 +560,179 @@ mod tests {
 
         Ok(())
     }
+
+    pub(crate) fn gen_clustered_particles(seed: u64, cluster_count: usize, cluster_origins: &[[f32; 3]], cluster_width: f32) -> Vec<Vec3A> {
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(seed);
+
+        cluster_origins
+            .iter()
+            .flat_map(|origin| std::iter::repeat_n(origin, cluster_count))
+            .map(|origin| {
+                Vec3A::new(
+                    origin[0] + rng.gen_range(0.0..cluster_width),
+                    origin[1] + rng.gen_range(0.0..cluster_width),
+                    origin[2] + rng.gen_range(0.0..cluster_width),
+                )
+            })
+            .collect()
+    }
+
+    pub(crate) fn neighbors_cpu(particles: &[Vec3A], search_radius: f32) -> Vec<Vec<u32>> {
+        neighbors_cpu_periodic(particles, search_radius, [None; 3])
+    }
+
+    /// Brute force reference, using the minimum image along the axes with a period.
+    pub(crate) fn neighbors_cpu_periodic(particles: &[Vec3A], search_radius: f32, period: [Option<f32>; 3]) -> Vec<Vec<u32>> {
+        let radius_sq = search_radius * search_radius;
+        let min_image = |delta: f32, axis: usize| match period[axis] {
+            Some(length) => delta - length * (delta / length).round(),
+            None => delta,
+        };
+
+        particles
+            .iter()
+            .enumerate()
+            .map(|(i, a)| {
+                particles
+                    .iter()
+                    .enumerate()
+                    .filter(|&(j, b)| {
+                        let (dx, dy, dz) = (min_image(b.x - a.x, 0), min_image(b.y - a.y, 1), min_image(b.z - a.z, 2));
+                        i != j && dx * dx + dy * dy + dz * dz <= radius_sq
+                    })
+                    .map(|(j, _)| j as u32)
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn check_fenns_search() -> anyhow::Result<()> {
+        let engine = Engine::new().await?;
+
+        for seed in 0..4 {
+            for search_radius in [0.2, 0.5, 1.0] {
+                println!("Seed: {}, search radius: {}", seed, search_radius);
+                check_fenns_search_inner(&engine, seed, search_radius).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn check_fenns_search_inner(engine: &Engine, seed: u64, search_radius: f32) -> anyhow::Result<()> {
+        const GRID_DIM: [usize; 3] = [14, 20, 9];
+        const GRID_SIZE: usize = GRID_DIM[0] * GRID_DIM[1] * GRID_DIM[2];
+        const CELL_SIZE: f32 = 1.0;
+        const MAX_NEIGHBORS: usize = 64;
+
+        // two dense clusters touching opposite corners of the grid
+        let particles = gen_clustered_particles(seed, 4000, &[[0.0, 0.0, 0.0], [10.0, 16.0, 5.0]], 4.0);
+
+        let params_buf = engine
+            .device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("fenns_search/buf0"),
+                contents: bytemuck::bytes_of(&FennsParams::new(GRID_DIM.map(|dim| dim as u32), CELL_SIZE, search_radius)),
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            });
+
+        let particles_buf = engine
+            .device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("fenns_search/buf1"),
+                contents: bytemuck::cast_slice(&particles),
+                usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+            });
+
+        let count_buf = engine.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("fenns_search/buf2"),
+            size: 4 * 2 * GRID_SIZE as u64,
+            mapped_at_creation: false,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+        });
+
+        let strays_buf = engine.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("fenns_search/strays"),
+            size: 4 * 2,
+            mapped_at_creation: false,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+        });
+
+        let reordered_buf = engine.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("fenns_search/buf3"),
+            size: particles.len() as u64 * 16,
+            mapped_at_creation: false,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+        });
+
+        let order_buf = engine.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("fenns_search/order"),
+            size: particles.len() as u64 * 4,
+            mapped_at_creation: false,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+        });
+
+        let inverse_order_buf = engine.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("fenns_search/inverse_order"),
+            size: particles.len() as u64 * 4,
+            mapped_at_creation: false,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+        });
+
+        let neighbor_count_buf = engine.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("fenns_search/buf4"),
+            size: particles.len() as u64 * 4,
+            mapped_at_creation: false,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+        });
+
+        let neighbors_buf = engine.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("fenns_search/buf5"),
+            size: (particles.len() * MAX_NEIGHBORS) as u64 * 4,
+            mapped_at_creation: false,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+        });
+
+        engine.fenns_sort1(&[&params_buf, &particles_buf, &count_buf, &strays_buf]);
+        engine.prefix_sum_inner::<u32>(&count_buf, ScanKind::Exclusive);
+        engine.fenns_sort2(&[
+            &params_buf,
+            &particles_buf,
+            &count_buf,
+            &reordered_buf,
+            &strays_buf,
+            &order_buf,
+            &inverse_order_buf,
+        ]);
+        engine.fenns_search(&[&params_buf, &reordered_buf, &count_buf, &neighbor_count_buf, &neighbors_buf]);
+
+        let reordered: Vec<Vec3A> = engine.map_buffer(&reordered_buf).await?;
+        let neighbor_counts: Vec<u32> = engine.map_buffer(&neighbor_count_buf).await?;
+        let neighbors: Vec<u32> = engine.map_buffer(&neighbors_buf).await?;
+
+        let expected = neighbors_cpu(&reordered, search_radius);
+        let found_counts: Vec<u32> = expected.iter().map(|n| n.len() as u32).collect();
+        assert_slices_eq(&neighbor_counts, &found_counts);
+
+        for (i, expected) in expected.iter().enumerate() {
+            let stored = expected.len().min(MAX_NEIGHBORS);
+            let found = &neighbors[i * MAX_NEIGHBORS..i * MAX_NEIGHBORS + stored];
+
+            if let Some(j) = found.iter().find(|j| !expected.contains(j)) {
+                panic!(
+                    "Particle #{} {:?} reported #{} {:?} as a neighbor, expected one of {:?}",
+                    i, reordered[i], j, reordered[*j as usize], expected
+                );
+            }
+
+            if expected.len() <= MAX_NEIGHBORS {
+                let mut found = found.to_vec();
+                found.sort();
+                assert_slices_eq(&found, expected);
+            }
+        }
+
+        Ok(())
+    }
 }
@@ -0,0 +1,250 @@
+use wgpu::util::DeviceExt;
+
+use crate::pipeline::{STORAGE, STORAGE_READ, UNIFORM};
+use crate::Engine;
+
+const HISTOGRAM: &str = include_str!("kernels/histogram.wgsl");
+
+const KEY_BINS: &str = "
+    alias Input = u32;
+    fn bin_of(key: u32) -> u32 { return key; }
+";
+
+/// Binary searches the edges, NaNs and values outside of them end up past the last bin.
+const EDGE_BINS: &str = "
+    alias Input = f32;
+    @group(0) @binding(3)
+    var<storage, read> edges: array<f32>;
+    fn bin_of(value: f32) -> u32 {
+        if !(value >= edges[0] && value < edges[params.bin_count]) {
+            return params.bin_count;
+        }
+        var low = 0u;
+        var high = params.bin_count;
+        while high - low > 1u {
+            let mid = (low + high) / 2u;
+            if edges[mid] <= value {
+                low = mid;
+            } else {
+                high = mid;
+            }
+        }
+        return low;
+    }
+";
+
+/// The elements of each workgroup of the privatized histogram kernel, see histogram.wgsl.
+const TILE_LEN: u64 = 256 * 16;
+const WG_SIZE: u64 = 256;
+const MAX_WORKGROUPS: u64 = 65535;
+
+/// What [`Engine::histogram`] counts, either as host slices or device buffers.
+#[derive(Copy, Clone, Debug)]
+pub enum Bins<K, V> {
+    /// `u32` keys, each counted in the bin of its value. Keys past the last bin are dropped.
+    Keys(K),
+    /// `f32` values, counted in bin `i` when `edges[i] <= value < edges[i + 1]`. The edges are
+    /// sorted and there is one more of them than bins. Values outside of the edges and NaNs are
+    /// dropped.
+    Edges { values: V, edges: V },
+}
+
+impl Engine {
+    /// Counts the elements of `input` in each of `bin_count` bins.
+    pub async fn histogram(&self, input: Bins<&[u32], &[f32]>, bin_count: u32) -> anyhow::Result<Vec<u32>> {
+        let (input_slice, edges_slice): (&[u8], _) = match input {
+            Bins::Keys(keys) => (bytemuck::cast_slice(keys), None),
+            Bins::Edges { values, edges } => {
+                anyhow::ensure!(
+                    edges.len() == bin_count as usize + 1,
+                    "expected {} edges for {} bins, got {}",
+                    bin_count as usize + 1,
+                    bin_count,
+                    edges.len(),
+                );
+                anyhow::ensure!(edges.windows(2).all(|w| w[0] <= w[1]), "bin edges must be sorted");
+                (bytemuck::cast_slice(values), Some(bytemuck::cast_slice(edges)))
+            }
+        };
+
+        anyhow::ensure!(bin_count > 0, "a histogram needs at least one bin");
+        if input_slice.is_empty() {
+            return Ok(vec![0; bin_count as usize]);
+        }
+
+        let input_buf = self.histogram_buffer("histogram input", input_slice);
+        let counts = match edges_slice {
+            None => self.histogram_inner(Bins::Keys(&input_buf), bin_count)?,
+            Some(edges) => {
+                let edges_buf = self.histogram_buffer("histogram edges", edges);
+                self.histogram_inner(Bins::Edges { values: &input_buf, edges: &edges_buf }, bin_count)?
+            }
+        };
+
+        self.map_buffer(&counts).await
+    }
+
+    /// Counts the elements of a buffer in each of `bin_count` bins, into a new buffer of `u32`s.
+    /// Each workgroup counts its elements in workgroup memory first if the bins fit there and
+    /// are no more than its elements, and straight in the new buffer otherwise.
+    pub fn histogram_inner(&self, input: Bins<&wgpu::Buffer, &wgpu::Buffer>, bin_count: u32) -> anyhow::Result<wgpu::Buffer> {
+        anyhow::ensure!(bin_count > 0, "a histogram needs at least one bin");
+
+        let mut bindings = vec![UNIFORM, STORAGE_READ, STORAGE];
+        let (values, edges, bins) = match input {
+            Bins::Keys(keys) => (keys, None, KEY_BINS),
+            Bins::Edges { values, edges } => {
+                anyhow::ensure!(
+                    edges.size() == 4 * (bin_count as u64 + 1),
+                    "expected {} edges for {} bins, got {} bytes of edges",
+                    bin_count as u64 + 1,
+                    bin_count,
+                    edges.size(),
+                );
+                bindings.push(STORAGE_READ);
+                (values, Some(edges), EDGE_BINS)
+            }
+        };
+
+        let counts = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("histogram counts"),
+            size: 4 * bin_count as u64,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+
+        let len = values.size() / 4;
+        if len == 0 {
+            return Ok(counts);
+        }
+
+        let params = self
+            .device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("histogram params"),
+                contents: bytemuck::cast_slice(&[bin_count, 0, 0, 0]),
+                usage: wgpu::BufferUsages::UNIFORM,
+            });
+        let mut bufs = vec![&params, values, &counts];
+        bufs.extend(edges);
+
+        // the workgroup memory is sized to the bins, leaving room for more workgroups
+        let shared_bins = (bin_count as u64).next_power_of_two().max(WG_SIZE);
+        let privatized = shared_bins <= self.capabilities.histogram_shared_bins && bin_count as u64 <= len.min(TILE_LEN);
+        let (entry_point, workgroups) = if privatized {
+            ("main", len.div_ceil(TILE_LEN))
+        } else {
+            ("main_global", len.div_ceil(WG_SIZE))
+        };
+
+        let input_kind = if edges.is_some() { "edges" } else { "keys" };
+        let pipeline = self.specialized_pipeline(
+            "histogram",
+            &format!("{}, {} shared bins", input_kind, shared_bins),
+            || format!("{}\nconst SHARED_BINS: u32 = {}u;\n{}", bins, shared_bins, HISTOGRAM),
+            entry_point,
+            &bindings,
+        );
+        let bind_group = self.bind_buffers(&pipeline, &bufs);
+
+        let mut encoder = self.device.create_command_encoder(&Default::default());
+        {
+            let mut cpass = encoder.begin_compute_pass(&Default::default());
+            cpass.insert_debug_marker(&format!("histogram {} dispatch", entry_point));
+            cpass.set_pipeline(&pipeline.pipeline);
+            cpass.set_bind_group(0, &bind_group, &[]);
+            cpass.dispatch_workgroups(
+                workgroups.min(MAX_WORKGROUPS) as u32,
+                workgroups.div_ceil(MAX_WORKGROUPS) as u32,
+                1,
+            );
+        }
+
+        self.queue.submit(Some(encoder.finish()));
+
+        Ok(counts)
+    }
+
+    fn histogram_buffer(&self, label: &str, contents: &[u8]) -> wgpu::Buffer {
+        self.device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some(label),
+                contents,
+                usage: wgpu::BufferUsages::STORAGE,
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::assert_slices_eq;
+
+    use rand::Rng;
+    use rand_xoshiro::{rand_core::SeedableRng, Xoshiro256PlusPlus};
+
+    fn histogram_cpu(bins: impl Iterator<Item = Option<usize>>, bin_count: u32) -> Vec<u32> {
+        let mut counts = vec![0; bin_count as usize];
+        for bin in bins.flatten() {
+            if bin < counts.len() {
+                counts[bin] += 1;
+            }
+        }
+        counts
+    }
+
+    #[tokio::test]
+    async fn key_histogram_works() -> anyhow::Result<()> {
+        let engine = Engine::new().await?;
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(0);
+
+        // privatized and global bins, with keys past the last bin
+        for len in [1, 255, 5000, 1 << 20] {
+            for bin_count in [1, 16, 1000, 100_000] {
+                let keys: Vec<u32> = (0..len).map(|_| rng.gen_range(0..bin_count + bin_count / 10 + 1)).collect();
+                let expected = histogram_cpu(keys.iter().map(|&key| Some(key as usize)), bin_count);
+
+                assert_slices_eq(&engine.histogram(Bins::Keys(&keys), bin_count).await?, &expected);
+            }
+        }
+
+        // every key in the same bin
+        let keys = vec![3; 100_000];
+        assert_slices_eq(&engine.histogram(Bins::Keys(&keys), 4).await?, &[0, 0, 0, 100_000]);
+
+        assert_eq!(engine.histogram(Bins::Keys(&[]), 3).await?, [0, 0, 0]);
+        assert!(engine.histogram(Bins::Keys(&[0]), 0).await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn edge_histogram_works() -> anyhow::Result<()> {
+        let engine = Engine::new().await?;
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(1);
+
+        let mut values: Vec<f32> = (0..100_000).map(|_| rng.gen_range(-1.5..1.5)).collect();
+        values.extend([f32::NAN, f32::INFINITY, f32::NEG_INFINITY, -1.0, 1.0, 0.5]);
+
+        for bin_count in [1, 10, 5000] {
+            let edges: Vec<f32> = (0..=bin_count).map(|i| -1.0 + 2.0 * i as f32 / bin_count as f32).collect();
+            let bins = values.iter().map(|&value| edges.windows(2).position(|w| w[0] <= value && value < w[1]));
+            let expected = histogram_cpu(bins, bin_count);
+
+            let result = engine.histogram(Bins::Edges { values: &values, edges: &edges }, bin_count).await?;
+            assert_slices_eq(&result, &expected);
+        }
+
+        // uneven and empty bins
+        let edges = [0.0, 0.0, 0.1, 0.2, 1.0];
+        let values = [0.0, 0.05, 0.1, 0.15, 0.999, 1.0, -0.0];
+        let result = engine.histogram(Bins::Edges { values: &values, edges: &edges }, 4).await?;
+        assert_eq!(result, [0, 3, 2, 1]);
+
+        let edges = [1.0, 0.0];
+        assert!(engine.histogram(Bins::Edges { values: &values, edges: &edges }, 1).await.is_err());
+        assert!(engine.histogram(Bins::Edges { values: &values, edges: &edges }, 2).await.is_err());
+
+        Ok(())
+    }
+}
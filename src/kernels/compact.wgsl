@@ -0,0 +1,52 @@
+// Element and keep() are prepended for the predicate by Engine::compact_inner, along with the
+// flags binding if there is one
+
+@group(0) @binding(0)
+var<storage, read> input: array<Element>;
+
+// whether each element is kept, then the exclusive prefix sum of that
+@group(0) @binding(1)
+var<storage, read_write> offsets: array<u32>;
+
+@group(0) @binding(2)
+var<storage, read_write> output: array<Element>;
+
+@group(0) @binding(3)
+var<storage, read_write> count: u32;
+
+const WG_SIZE: u32 = 256;
+
+// the dispatch is split along y when it has too many workgroups
+fn element_idx(global_id: vec3u, num_workgroups: vec3u) -> u32 {
+    return global_id.y * num_workgroups.x * WG_SIZE + global_id.x;
+}
+
+@compute @workgroup_size(WG_SIZE)
+fn mark(
+    @builtin(global_invocation_id) global_id: vec3u,
+    @builtin(num_workgroups) num_workgroups: vec3u,
+) {
+    let idx = element_idx(global_id, num_workgroups);
+    if idx < arrayLength(&input) {
+        offsets[idx] = select(0u, 1u, keep(idx));
+    }
+}
+
+@compute @workgroup_size(WG_SIZE)
+fn scatter(
+    @builtin(global_invocation_id) global_id: vec3u,
+    @builtin(num_workgroups) num_workgroups: vec3u,
+) {
+    let idx = element_idx(global_id, num_workgroups);
+    if idx >= arrayLength(&input) {
+        return;
+    }
+
+    let kept = keep(idx);
+    if kept {
+        output[offsets[idx]] = input[idx];
+    }
+    if idx == arrayLength(&input) - 1u {
+        count = offsets[idx] + select(0u, 1u, kept);
+    }
+}
@@ -0,0 +1,29 @@
+// original index of each reordered particle, as written by fenns_sort2
+@group(0) @binding(0)
+var<storage, read> order: array<u32>;
+
+// per-particle attributes, each one a fixed number of u32 words
+@group(0) @binding(1)
+var<storage, read> input: array<u32>;
+
+@group(0) @binding(2)
+var<storage, read_write> reordered: array<u32>;
+
+const WG_SIZE: u32 = 256;
+@compute @workgroup_size(WG_SIZE)
+fn main(
+    @builtin(global_invocation_id) global_id: vec3u,
+    @builtin(num_workgroups) num_workgroups: vec3u,
+) {
+    // one word per thread, the dispatch is split along y when it has too many workgroups
+    let idx = global_id.y * num_workgroups.x * WG_SIZE + global_id.x;
+    if idx >= arrayLength(&reordered) {
+        return;
+    }
+
+    let stride = arrayLength(&input) / arrayLength(&order);
+    let particle = idx / stride;
+    let word = idx % stride;
+
+    reordered[idx] = input[order[particle] * stride + word];
+}
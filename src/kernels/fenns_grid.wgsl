@@ -0,0 +1,83 @@
+// Prepended to fenns_sort1, fenns_sort2 and fenns_search by Engine::new, so that they place
+// particles in the same cells
+
+// mirrors FennsParams in fenns.rs
+struct Params {
+    origin: vec3f,
+    cell_width: f32,
+    grid_dim: vec3u,
+    search_radius: f32,
+    out_of_bounds: u32,
+    // one bit per axis
+    periodic: u32,
+    // cells are hashed into this many slots, 0 for the dense grid
+    table_size: u32,
+}
+
+// must match OutOfBounds in fenns.rs
+const OUT_OF_BOUNDS_CLAMP: u32 = 0u;
+
+@group(0) @binding(0)
+var<uniform> params: Params;
+
+struct Particle {
+    position: vec3f,
+}
+
+// must match FENNS_WG_SIZE in fenns.rs
+const WG_SIZE: u32 = 64;
+
+// the dispatches are split along y when they have too many workgroups
+fn particle_idx(global_id: vec3u, num_workgroups: vec3u) -> u32 {
+    return global_id.y * num_workgroups.x * WG_SIZE + global_id.x;
+}
+
+fn periodic_axes() -> vec3<bool> {
+    return ((vec3u(params.periodic) >> vec3u(0u, 1u, 2u)) & vec3u(1u)) != vec3u(0u);
+}
+
+// positions along periodic axes are wrapped into the grid
+fn grid_rel_pos(particle: Particle) -> vec3f {
+    let rel_pos = (particle.position - params.origin) / params.cell_width;
+    let grid_dim = vec3f(params.grid_dim);
+    return select(rel_pos, rel_pos - grid_dim * floor(rel_pos / grid_dim), periodic_axes());
+}
+
+// hashed cells cover all of space
+fn in_grid(rel_pos: vec3f) -> bool {
+    let inside = (rel_pos >= vec3f(0.0)) & (rel_pos < vec3f(params.grid_dim));
+    return params.table_size != 0u || all(inside | periodic_axes());
+}
+
+// cells are counted in hash table slots instead, if there is a table
+fn grid_cell_count() -> u32 {
+    if params.table_size != 0u {
+        return params.table_size;
+    }
+    return params.grid_dim.x * params.grid_dim.y * params.grid_dim.z;
+}
+
+// clamps into the edge cells unless hashed, NaN coordinates end up in the first one
+fn grid_cell_pos(rel_pos: vec3f) -> vec3i {
+    if params.table_size != 0u {
+        return vec3i(floor(rel_pos));
+    }
+    let lower = select(vec3f(0.0), floor(rel_pos), rel_pos >= vec3f(0.0));
+    return vec3i(min(lower, vec3f(params.grid_dim - 1u)));
+}
+
+fn grid_cell_idx(cell_pos: vec3i) -> u32 {
+    if params.table_size != 0u {
+        let hash = vec3u(cell_pos) * vec3u(73856093u, 19349663u, 83492791u);
+        return (hash.x ^ hash.y ^ hash.z) % params.table_size;
+    }
+    let grid_dim = vec3i(params.grid_dim);
+    return u32(cell_pos.z * grid_dim.y * grid_dim.x + cell_pos.y * grid_dim.x + cell_pos.x);
+}
+
+// within search_radius / 2 of a face of its cell
+fn is_border(rel_pos: vec3f, cell_pos: vec3i) -> bool {
+    let inner_size = params.cell_width - params.search_radius;
+    let cell_offset = (rel_pos - vec3f(cell_pos) - 0.5) * params.cell_width;
+    return any(abs(cell_offset) > vec3f(inner_size / 2.0));
+}
@@ -0,0 +1,120 @@
+// Params, Particle and the grid functions are prepended from fenns_grid.wgsl by Engine::new
+
+// must be sorted by fenns_sort2
+@group(0) @binding(1)
+var<storage, read> particles: array<Particle>;
+
+// the end of the border and the interior section of each cell, interleaved
+@group(0) @binding(2)
+var<storage, read> count: array<u32>;
+
+@group(0) @binding(3)
+var<storage, read_write> neighbor_count: array<u32>;
+
+@group(0) @binding(4)
+var<storage, read_write> neighbors: array<u32>;
+
+// a cell starts where the previous one ends
+fn cell_start(cell_idx: u32) -> u32 {
+    if cell_idx == 0u {
+        return 0u;
+    }
+    return count[2u * cell_idx - 1u];
+}
+
+fn cell_split(cell_idx: u32) -> u32 {
+    return count[2u * cell_idx];
+}
+
+fn cell_end(cell_idx: u32) -> u32 {
+    return count[2u * cell_idx + 1u];
+}
+
+@compute @workgroup_size(WG_SIZE)
+fn main(
+    @builtin(global_invocation_id) global_id: vec3u,
+    @builtin(num_workgroups) num_workgroups: vec3u,
+) {
+    let idx = particle_idx(global_id, num_workgroups);
+    let particle_count = arrayLength(&particles);
+    if idx >= particle_count {
+        return;
+    }
+
+    // particles dropped by fenns_sort2 are past the last cell
+    if idx >= cell_start(grid_cell_count()) {
+        neighbor_count[idx] = 0u;
+        return;
+    }
+
+    let max_neighbors = arrayLength(&neighbors) / particle_count;
+    let grid_dim = vec3i(params.grid_dim);
+    let particle = particles[idx];
+    let rel_pos = grid_rel_pos(particle);
+    let grid_pos = grid_cell_pos(rel_pos);
+
+    let border = is_border(rel_pos, grid_pos);
+
+    let periodic = periodic_axes();
+    let period = vec3f(params.grid_dim) * params.cell_width;
+
+    let radius_sq = params.search_radius * params.search_radius;
+    var found = 0u;
+
+    let hashed = params.table_size != 0u;
+    var visited: array<u32, 27>;
+
+    for (var i = 0u; i < 27u; i += 1u) {
+        // starts with the particle's own cell
+        let n = (i + 13u) % 27u;
+        let offset = vec3i(i32(n % 3u), i32(n / 3u % 3u), i32(n / 9u)) - 1;
+
+        var neighbor_pos = grid_pos + offset;
+        if !hashed {
+            neighbor_pos = select(neighbor_pos, (neighbor_pos + grid_dim) % grid_dim, periodic);
+            if any(neighbor_pos < vec3i(0)) || any(neighbor_pos >= grid_dim) {
+                continue;
+            }
+        }
+
+        let cell_idx = grid_cell_idx(neighbor_pos);
+
+        // hashed cells can share a slot, which must only be scanned once
+        if hashed {
+            var seen = false;
+            for (var k = 0u; k < i; k += 1u) {
+                seen = seen || visited[k] == cell_idx;
+            }
+            visited[i] = cell_idx;
+            if seen {
+                continue;
+            }
+        }
+
+        // any pair of neighbors in different cells has at least one of them within
+        // search_radius / 2 of a cell face, so interior particles only need to look
+        // at the border section of the surrounding cells
+        var end = cell_end(cell_idx);
+        if !border && i != 0u {
+            end = cell_split(cell_idx);
+        }
+
+        for (var j = cell_start(cell_idx); j < end; j += 1u) {
+            if j == idx {
+                continue;
+            }
+
+            // minimum image along the periodic axes
+            var delta = particles[j].position - particle.position;
+            delta = select(delta, delta - period * round(delta / period), periodic);
+            if dot(delta, delta) <= radius_sq {
+                if found < max_neighbors {
+                    neighbors[idx * max_neighbors + found] = j;
+                }
+                found += 1u;
+            }
+        }
+    }
+
+    neighbor_count[idx] = found;
+}
@@ -1,44 +1,72 @@
-struct Params {
-    cell_width: f32,
-    search_radius: f32,
-}
-
-@group(0) @binding(0)
-var<uniform> params: Params;
-
-struct Particle {
-    position: vec3f,
-}
+// Params, Particle and the grid functions are prepended from fenns_grid.wgsl by Engine::new
 
 @group(0) @binding(1)
 var<storage, read> input: array<Particle>;
 
+// the border and interior particles of each cell, interleaved
 @group(0) @binding(2)
 var<storage, read_write> count: array<atomic<u32>>;
 
-const GRID_DIM: u32 = 18;
-const GRID_SIZE: u32 = GRID_DIM * GRID_DIM * GRID_DIM;
-var<workgroup> shCount: array<atomic<u32>, GRID_SIZE>;
+// [particles outside of the grid, cursor used by fenns_sort2]
+@group(0) @binding(3)
+var<storage, read_write> strays: array<atomic<u32>, 2>;
+
+// SHARED_COUNTS is prepended by Engine::new to fill max_compute_workgroup_storage_size
+var<workgroup> shCount: array<atomic<u32>, SHARED_COUNTS>;
 
-const WG_SIZE: u32 = 64;
+// returns the count to add the particle to, or slots if it has to be dropped
+fn count_particle(particle: Particle, slots: u32) -> u32 {
+    let rel_pos = grid_rel_pos(particle);
+    if !in_grid(rel_pos) {
+        atomicAdd(&strays[0], 1u);
+        if params.out_of_bounds != OUT_OF_BOUNDS_CLAMP {
+            return slots;
+        }
+    }
+    let cell_pos = grid_cell_pos(rel_pos);
+    return 2u * grid_cell_idx(cell_pos) + select(1u, 0u, is_border(rel_pos, cell_pos));
+}
+
+// for grids of up to SHARED_COUNTS / 2 cells
 @compute @workgroup_size(WG_SIZE)
 fn main(
     @builtin(global_invocation_id) global_id: vec3u,
+    @builtin(num_workgroups) num_workgroups: vec3u,
     @builtin(local_invocation_id) local_id: vec3u,
 ) {
-    if global_id.x < arrayLength(&input) {
-        let particle = input[global_id.x];
-        let grid_pos = vec3u(particle.position / params.cell_width);
-        let grid_cell_idx = grid_pos.z * GRID_DIM * GRID_DIM + grid_pos.y * GRID_DIM + grid_pos.x;
-        atomicAdd(&shCount[grid_cell_idx], 1u);
+    let idx = particle_idx(global_id, num_workgroups);
+    let slots = 2u * grid_cell_count();
+
+    if idx < arrayLength(&input) {
+        let slot = count_particle(input[idx], slots);
+        if slot < slots {
+            atomicAdd(&shCount[slot], 1u);
+        }
     }
     workgroupBarrier();
 
-    for (var i = 0u; i <= GRID_SIZE / WG_SIZE; i += 1u) {
+    for (var i = 0u; i <= slots / WG_SIZE; i += 1u) {
         let offset = i * WG_SIZE + local_id.x;
-        if offset < GRID_SIZE {
+        if offset < slots {
             let particleCount = atomicLoad(&shCount[offset]);
             atomicAdd(&count[offset], particleCount);
         }
     }
-}
\ No newline at end of file
+}
+
+// for grids that don't fit in workgroup memory
+@compute @workgroup_size(WG_SIZE)
+fn main_global(
+    @builtin(global_invocation_id) global_id: vec3u,
+    @builtin(num_workgroups) num_workgroups: vec3u,
+) {
+    let idx = particle_idx(global_id, num_workgroups);
+    let slots = 2u * grid_cell_count();
+
+    if idx < arrayLength(&input) {
+        let slot = count_particle(input[idx], slots);
+        if slot < slots {
+            atomicAdd(&count[slot], 1u);
+        }
+    }
+}
@@ -1,63 +1,57 @@
-struct Params {
-    cell_width: f32,
-    search_radius: f32,
-}
-
-@group(0) @binding(0)
-var<uniform> params: Params;
-
-struct Particle {
-    position: vec3f,
-}
+// Params, Particle and the grid functions are prepended from fenns_grid.wgsl by Engine::new
 
 @group(0) @binding(1)
 var<storage, read> input: array<Particle>;
 
+// the start of the border and the interior section of each cell, interleaved
 @group(0) @binding(2)
 var<storage, read_write> count: array<atomic<u32>>;
 
 @group(0) @binding(3)
 var<storage, read_write> reordered: array<Particle>;
 
-const GRID_DIM: u32 = 18;
-const GRID_SIZE: u32 = GRID_DIM * GRID_DIM * GRID_DIM;
-var<workgroup> shCount: array<atomic<u32>, GRID_SIZE>;
+// [particles outside of the grid, cursor used by fenns_sort2]
+@group(0) @binding(4)
+var<storage, read_write> strays: array<atomic<u32>, 2>;
+
+// original index of each reordered particle
+@group(0) @binding(5)
+var<storage, read_write> order: array<u32>;
+
+// reordered index of each original particle
+@group(0) @binding(6)
+var<storage, read_write> inverse_order: array<u32>;
 
-const WG_SIZE: u32 = 64;
 @compute @workgroup_size(WG_SIZE)
 fn main(
     @builtin(global_invocation_id) global_id: vec3u,
-    @builtin(local_invocation_id) local_id: vec3u,
+    @builtin(num_workgroups) num_workgroups: vec3u,
 ) {
-    if global_id.x < arrayLength(&input) {
-        let particle = input[global_id.x];
-        let gridPos = vec3u(particle.position / params.cell_width);
-        let gridCellIdx = gridPos.z * GRID_DIM * GRID_DIM + gridPos.y * GRID_DIM + gridPos.x;
+    let idx = particle_idx(global_id, num_workgroups);
+    if idx < arrayLength(&input) {
+        let particle = input[idx];
+        let relPos = grid_rel_pos(particle);
+
+        // dropped particles are packed at the end, after all the cells
+        if !in_grid(relPos) && params.out_of_bounds != OUT_OF_BOUNDS_CLAMP {
+            let strayPos = arrayLength(&input) - 1u - atomicAdd(&strays[1], 1u);
+            reordered[strayPos] = particle;
+            order[strayPos] = idx;
+            inverse_order[idx] = strayPos;
+            return;
+        }
 
-        let innerSize = params.cell_width - params.search_radius;
-        let cellCenter = vec3f(params.cell_width / 2.0) + vec3f(gridPos) * params.cell_width;
-        let isBorder = any(abs(cellCenter - particle.position) > vec3f(innerSize / 2.0));
+        let gridPos = grid_cell_pos(relPos);
+        let gridCellIdx = grid_cell_idx(gridPos);
 
-        if isBorder {
-            atomicAdd(&shCount[gridCellIdx], 1u);
-        }
+        let isBorder = is_border(relPos, gridPos);
 
-        var reorderedPos: u32;
-        if isBorder {
-            reorderedPos = atomicAdd(&count[GRID_SIZE + gridCellIdx], 1u);
-        } else {
-            reorderedPos = atomicSub(&count[gridCellIdx], 1u) - 1;
-        }
+        // border particles come first in their cell; once every particle is placed each
+        // cursor has moved on to the end of its section
+        let reorderedPos = atomicAdd(&count[2u * gridCellIdx + select(1u, 0u, isBorder)], 1u);
 
         reordered[reorderedPos] = particle;
-    }
-    storageBarrier();
-    
-    for (var i = 0u; i <= GRID_SIZE / WG_SIZE; i += 1u) {
-        let offset = i * WG_SIZE + local_id.x;
-        if offset < GRID_SIZE {
-            let particle_count = atomicLoad(&shCount[offset]);
-            atomicSub(&count[offset], particle_count);
-        }
+        order[reorderedPos] = idx;
+        inverse_order[idx] = reorderedPos;
     }
 }
\ No newline at end of file
@@ -0,0 +1,68 @@
+// Input, bin_of() and SHARED_BINS are prepended for the kind of input and the bin count by
+// Engine::histogram_inner, along with the edges binding for f32 values
+
+struct Params {
+    bin_count: u32,
+}
+
+@group(0) @binding(0)
+var<uniform> params: Params;
+
+@group(0) @binding(1)
+var<storage, read> input: array<Input>;
+
+@group(0) @binding(2)
+var<storage, read_write> counts: array<atomic<u32>>;
+
+var<workgroup> shared_counts: array<atomic<u32>, SHARED_BINS>;
+
+const WG_SIZE: u32 = 256;
+const ITEMS_PER_THREAD: u32 = 16;
+const TILE_LEN: u32 = WG_SIZE * ITEMS_PER_THREAD;
+
+// the dispatch is split along y when it has too many workgroups
+fn workgroup_idx(wg_id: vec3u, num_workgroups: vec3u) -> u32 {
+    return wg_id.y * num_workgroups.x + wg_id.x;
+}
+
+// counts a tile of elements in workgroup memory, then adds the bins to the global ones
+@compute @workgroup_size(WG_SIZE)
+fn main(
+    @builtin(local_invocation_index) local_idx: u32,
+    @builtin(workgroup_id) wg_id: vec3u,
+    @builtin(num_workgroups) num_workgroups: vec3u,
+) {
+    let first = workgroup_idx(wg_id, num_workgroups) * TILE_LEN;
+    for (var k = 0u; k < ITEMS_PER_THREAD; k += 1u) {
+        let idx = first + k * WG_SIZE + local_idx;
+        if idx < arrayLength(&input) {
+            let bin = bin_of(input[idx]);
+            if bin < params.bin_count {
+                atomicAdd(&shared_counts[bin], 1u);
+            }
+        }
+    }
+    workgroupBarrier();
+
+    for (var bin = local_idx; bin < params.bin_count; bin += WG_SIZE) {
+        let count = atomicLoad(&shared_counts[bin]);
+        if count != 0u {
+            atomicAdd(&counts[bin], count);
+        }
+    }
+}
+
+// for bins that don't fit in workgroup memory
+@compute @workgroup_size(WG_SIZE)
+fn main_global(
+    @builtin(global_invocation_id) global_id: vec3u,
+    @builtin(num_workgroups) num_workgroups: vec3u,
+) {
+    let idx = global_id.y * num_workgroups.x * WG_SIZE + global_id.x;
+    if idx < arrayLength(&input) {
+        let bin = bin_of(input[idx]);
+        if bin < params.bin_count {
+            atomicAdd(&counts[bin], 1u);
+        }
+    }
+}
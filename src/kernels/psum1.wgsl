@@ -1,34 +1,71 @@
+// Elem, identity(), combine(), scan_range, in_range() and buf_idx() are prepended for the element
+// type, operator and direction by Engine::scan_levels, followed by scan_tile.wgsl and a
+// scan_workgroup variant
+
+// the scanned elements start at scan_range.head, as bindings can only start at aligned offsets.
+// The kernels work on their index in the order of the scan, buf_idx() maps it into the binding
 @group(0) @binding(0)
-var<storage, read_write> buf: array<u32>;
+var<storage, read_write> buf: array<Elem>;
 
 @group(0) @binding(1)
-var<storage, read_write> next: array<u32>;
+var<storage, read_write> next: array<Elem>;
+
+// scans the tile, leaving the inclusive scan of the thread's items in `items`, and returns the
+// combined items of the preceding threads
+fn scan_tile(items: ptr<function, array<Elem, ITEMS_PER_THREAD>>, first: u32, invocation: ScanInvocation) -> Elem {
+    for (var k = 0u; k < ITEMS_PER_THREAD; k += 1u) {
+        (*items)[k] = identity();
+        if in_range(first + k) {
+            (*items)[k] = buf[buf_idx(first + k)];
+        }
+    }
 
-const WG_LEN : u32 = 256;
-var<workgroup> scratchpad: array<u32, WG_LEN>;
+    return scan_workgroup(scan_items(items), invocation);
+}
 
 @compute @workgroup_size(WG_LEN)
 fn main(
-    @builtin(global_invocation_id) global_id: vec3u,
-    @builtin(local_invocation_id) local_id: vec3u,
+    invocation: ScanInvocation,
     @builtin(workgroup_id) wg_id: vec3u,
 ){
-    var sum = buf[global_id.x];
-    scratchpad[local_id.x] = sum;
-    workgroupBarrier();
-    
-    for(var i = 0u; i < firstTrailingBit(WG_LEN); i += 1u){
-        workgroupBarrier();
-        if local_id.x >= (1u << i) {
-            sum += scratchpad[local_id.x - (1u << i)];
+    let local_idx = invocation.local_idx;
+    let first = wg_id.x * TILE_LEN + local_idx * ITEMS_PER_THREAD;
+    var items: array<Elem, ITEMS_PER_THREAD>;
+    let prefix = scan_tile(&items, first, invocation);
+
+    for (var k = 0u; k < ITEMS_PER_THREAD; k += 1u) {
+        if in_range(first + k) {
+            buf[buf_idx(first + k)] = combine(prefix, items[k]);
         }
-        workgroupBarrier();
-        scratchpad[local_id.x] = sum;
     }
 
-    buf[global_id.x] = sum;
+    if local_idx == WG_LEN - 1 {
+        next[wg_id.x] = workgroup_total;
+    }
+}
+
+// the workgroup totals passed on to the next level stay inclusive
+@compute @workgroup_size(WG_LEN)
+fn main_exclusive(
+    invocation: ScanInvocation,
+    @builtin(workgroup_id) wg_id: vec3u,
+){
+    let local_idx = invocation.local_idx;
+    let first = wg_id.x * TILE_LEN + local_idx * ITEMS_PER_THREAD;
+    var items: array<Elem, ITEMS_PER_THREAD>;
+    let prefix = scan_tile(&items, first, invocation);
+
+    for (var k = 0u; k < ITEMS_PER_THREAD; k += 1u) {
+        if in_range(first + k) {
+            var exclusive = prefix;
+            if k > 0u {
+                exclusive = combine(prefix, items[k - 1u]);
+            }
+            buf[buf_idx(first + k)] = exclusive;
+        }
+    }
 
-    if local_id.x == WG_LEN - 1 {
-        next[wg_id.x] = sum;
+    if local_idx == WG_LEN - 1 {
+        next[wg_id.x] = workgroup_total;
     }
-}
\ No newline at end of file
+}
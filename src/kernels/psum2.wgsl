@@ -1,16 +1,24 @@
+// Elem, identity(), combine(), scan_range, in_range() and buf_idx() are prepended for the element
+// type, operator and direction by Engine::scan_levels, followed by scan_tile.wgsl and a
+// scan_workgroup variant
+
+// the scanned elements start at scan_range.head and are indexed in the order of the scan, see
+// psum1.wgsl
 @group(0) @binding(0)
-var<storage, read_write> buf: array<u32>;
+var<storage, read_write> buf: array<Elem>;
 
 @group(0) @binding(1)
-var<storage, read_write> prev: array<u32>;
-
-// must be the same as psum1.wgsl
-const WG_LEN : u32 = 256;
+var<storage, read_write> prev: array<Elem>;
 
 @compute @workgroup_size(WG_LEN)
 fn main(
-    @builtin(global_invocation_id) global_id: vec3u,
+    @builtin(local_invocation_index) local_idx: u32,
     @builtin(workgroup_id) wg_id: vec3u,
 ){
-    buf[global_id.x] += prev[wg_id.x];
-}
\ No newline at end of file
+    for (var k = 0u; k < ITEMS_PER_THREAD; k += 1u) {
+        let idx = wg_id.x * TILE_LEN + k * WG_LEN + local_idx;
+        if in_range(idx) {
+            buf[buf_idx(idx)] = combine(prev[wg_id.x], buf[buf_idx(idx)]);
+        }
+    }
+}
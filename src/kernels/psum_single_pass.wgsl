@@ -0,0 +1,131 @@
+// Elem, identity(), combine(), scan_range, in_range(), buf_idx(), ELEM_WORDS, elem_to_words() and
+// elem_from_words() are prepended by Engine::scan_single_pass, followed by scan_tile.wgsl and a
+// scan_workgroup variant
+
+// the scanned elements start at scan_range.head and are indexed in the order of the scan, see
+// psum1.wgsl
+@group(0) @binding(0)
+var<storage, read_write> buf: array<Elem>;
+
+// [tile counter, then the aggregate and the inclusive prefix of every tile], zeroed before the scan
+@group(0) @binding(1)
+var<storage, read_write> state: array<atomic<u32>>;
+
+// Every word is published as two 16 bit halves, each one shifted up next to a ready bit. A half
+// can then be read on its own without any ordering between the atomics, which WGSL can't express
+// across workgroups.
+const HALVES: u32 = 2u * ELEM_WORDS;
+const AGGREGATE: u32 = 0u;
+const INCLUSIVE: u32 = 1u;
+
+fn slot_base(tile: u32, slot: u32) -> u32 {
+    return 1u + (tile * 2u + slot) * HALVES;
+}
+
+fn publish(tile: u32, slot: u32, value: Elem) {
+    var words = elem_to_words(value);
+    let base = slot_base(tile, slot);
+    for (var i = 0u; i < ELEM_WORDS; i += 1u) {
+        atomicStore(&state[base + 2u * i], ((words[i] & 0xffffu) << 1u) | 1u);
+        atomicStore(&state[base + 2u * i + 1u], ((words[i] >> 16u) << 1u) | 1u);
+    }
+}
+
+// returns false if some half hasn't been published yet
+fn try_load(tile: u32, slot: u32, value: ptr<function, Elem>) -> bool {
+    var words: array<u32, ELEM_WORDS>;
+    let base = slot_base(tile, slot);
+    for (var i = 0u; i < ELEM_WORDS; i += 1u) {
+        let low = atomicLoad(&state[base + 2u * i]);
+        let high = atomicLoad(&state[base + 2u * i + 1u]);
+        if (low & high & 1u) == 0u {
+            return false;
+        }
+        words[i] = (low >> 1u) | ((high >> 1u) << 16u);
+    }
+    *value = elem_from_words(words);
+    return true;
+}
+
+// combines the aggregates of the preceding tiles until one of them has its inclusive prefix
+fn look_back(tile: u32) -> Elem {
+    var exclusive = identity();
+    var prev = tile;
+    var found_inclusive = false;
+    while !found_inclusive {
+        prev -= 1u;
+        var value: Elem;
+        // the preceding tiles were all claimed earlier, so they're guaranteed to make progress
+        loop {
+            found_inclusive = try_load(prev, INCLUSIVE, &value);
+            if found_inclusive {
+                break;
+            }
+            if try_load(prev, AGGREGATE, &value) {
+                break;
+            }
+        }
+        exclusive = combine(value, exclusive);
+    }
+    return exclusive;
+}
+
+var<workgroup> tile_id: u32;
+var<workgroup> tile_prefix: Elem;
+
+// tiles are numbered in the order workgroups start rather than by workgroup_id
+fn scan_tile(invocation: ScanInvocation, exclusive: bool) {
+    let local_idx = invocation.local_idx;
+    if local_idx == 0u {
+        tile_id = atomicAdd(&state[0], 1u);
+    }
+    let tile = workgroupUniformLoad(&tile_id);
+    if tile >= (arrayLength(&buf) - scan_range.head + TILE_LEN - 1u) / TILE_LEN {
+        return;
+    }
+
+    let first = tile * TILE_LEN + local_idx * ITEMS_PER_THREAD;
+    var items: array<Elem, ITEMS_PER_THREAD>;
+    for (var k = 0u; k < ITEMS_PER_THREAD; k += 1u) {
+        items[k] = identity();
+        if in_range(first + k) {
+            items[k] = buf[buf_idx(first + k)];
+        }
+    }
+    let thread_prefix = scan_workgroup(scan_items(&items), invocation);
+
+    if local_idx == WG_LEN - 1u {
+        let sum = workgroup_total;
+        var prefix = identity();
+        if tile != 0u {
+            publish(tile, AGGREGATE, sum);
+            prefix = look_back(tile);
+        }
+        publish(tile, INCLUSIVE, combine(prefix, sum));
+        tile_prefix = prefix;
+    }
+    let prefix = combine(workgroupUniformLoad(&tile_prefix), thread_prefix);
+
+    for (var k = 0u; k < ITEMS_PER_THREAD; k += 1u) {
+        var result = combine(prefix, items[k]);
+        if exclusive {
+            result = prefix;
+            if k > 0u {
+                result = combine(prefix, items[k - 1u]);
+            }
+        }
+        if in_range(first + k) {
+            buf[buf_idx(first + k)] = result;
+        }
+    }
+}
+
+@compute @workgroup_size(WG_LEN)
+fn main(invocation: ScanInvocation) {
+    scan_tile(invocation, false);
+}
+
+@compute @workgroup_size(WG_LEN)
+fn main_exclusive(invocation: ScanInvocation) {
+    scan_tile(invocation, true);
+}
@@ -0,0 +1,113 @@
+// the Elem declarations, scan_tile.wgsl and a scan_workgroup variant are prepended by
+// Engine::sort_pairs_inner, along with the value bindings and move_value() when sorting pairs
+
+struct Params {
+    // of the digit sorted by this pass
+    shift: u32,
+}
+
+@group(0) @binding(0)
+var<uniform> params: Params;
+
+@group(0) @binding(1)
+var<storage, read> keys_in: array<u32>;
+
+// digit-major, the count of each digit in each tile and then their exclusive prefix sum
+@group(0) @binding(2)
+var<storage, read_write> counts: array<u32>;
+
+@group(0) @binding(3)
+var<storage, read_write> keys_out: array<u32>;
+
+const RADIX: u32 = 16u;
+
+fn tile_count() -> u32 {
+    return (arrayLength(&keys_in) + WG_LEN - 1u) / WG_LEN;
+}
+
+// tiles are split along y when there are too many
+fn tile_idx(wg_id: vec3u, num_workgroups: vec3u) -> u32 {
+    return wg_id.y * num_workgroups.x + wg_id.x;
+}
+
+fn digit_of(key: u32) -> u32 {
+    return (key >> params.shift) & (RADIX - 1u);
+}
+
+// a byte per digit, which is enough for the ranks as they stay below WG_LEN
+fn one_hot(digit: u32) -> vec4u {
+    var hot = vec4u(0u);
+    hot[digit / 4u] = 1u << (8u * (digit % 4u));
+    return hot;
+}
+
+fn digit_count(bytes: vec4u, digit: u32) -> u32 {
+    return (bytes[digit / 4u] >> (8u * (digit % 4u))) & 0xffu;
+}
+
+struct Ranked {
+    valid: bool,
+    digit: u32,
+    // the count of each digit among the preceding elements of the tile
+    preceding: vec4u,
+}
+
+// only the exclusive counts are used, the total of the workgroup may overflow the bytes
+fn rank_in_tile(idx: u32, invocation: ScanInvocation) -> Ranked {
+    let valid = idx < arrayLength(&keys_in);
+    var digit = 0u;
+    var hot = vec4u(0u);
+    if valid {
+        digit = digit_of(keys_in[idx]);
+        hot = one_hot(digit);
+    }
+
+    return Ranked(valid, digit, scan_workgroup(hot, invocation));
+}
+
+@compute @workgroup_size(WG_LEN)
+fn count(
+    invocation: ScanInvocation,
+    @builtin(workgroup_id) wg_id: vec3u,
+    @builtin(num_workgroups) num_workgroups: vec3u,
+) {
+    let tile = tile_idx(wg_id, num_workgroups);
+    if tile >= tile_count() {
+        return;
+    }
+
+    let idx = tile * WG_LEN + invocation.local_idx;
+    let ranked = rank_in_tile(idx, invocation);
+
+    // the last thread has seen all the others
+    if invocation.local_idx == WG_LEN - 1u {
+        for (var digit = 0u; digit < RADIX; digit += 1u) {
+            var total = digit_count(ranked.preceding, digit);
+            if ranked.valid && ranked.digit == digit {
+                total += 1u;
+            }
+            counts[digit * tile_count() + tile] = total;
+        }
+    }
+}
+
+@compute @workgroup_size(WG_LEN)
+fn scatter(
+    invocation: ScanInvocation,
+    @builtin(workgroup_id) wg_id: vec3u,
+    @builtin(num_workgroups) num_workgroups: vec3u,
+) {
+    let tile = tile_idx(wg_id, num_workgroups);
+    if tile >= tile_count() {
+        return;
+    }
+
+    let idx = tile * WG_LEN + invocation.local_idx;
+    let ranked = rank_in_tile(idx, invocation);
+
+    if ranked.valid {
+        let pos = counts[ranked.digit * tile_count() + tile] + digit_count(ranked.preceding, ranked.digit);
+        keys_out[pos] = keys_in[idx];
+        move_value(idx, pos);
+    }
+}
@@ -0,0 +1,65 @@
+// Value, Elem, identity(), combine() and to_elem() are prepended by Engine::reduce_levels,
+// followed by scan_tile.wgsl and a scan_workgroup variant
+
+// the values to reduce, only read by `main`
+@group(0) @binding(0)
+var<storage, read> input: array<Value>;
+
+// the totals of the previous level, only read by `main_partials`
+@group(0) @binding(1)
+var<storage, read> partials: array<Elem>;
+
+@group(0) @binding(2)
+var<storage, read_write> totals: array<Elem>;
+
+// tiles are split along y when there are too many
+fn tile_idx(wg_id: vec3u, num_workgroups: vec3u) -> u32 {
+    return wg_id.y * num_workgroups.x + wg_id.x;
+}
+
+fn reduce_tile(total: Elem, invocation: ScanInvocation, tile: u32) {
+    scan_workgroup(total, invocation);
+
+    // the last row of workgroups can extend past the tiles
+    if invocation.local_idx == 0u && tile < arrayLength(&totals) {
+        totals[tile] = workgroup_total;
+    }
+}
+
+@compute @workgroup_size(WG_LEN)
+fn main(
+    invocation: ScanInvocation,
+    @builtin(workgroup_id) wg_id: vec3u,
+    @builtin(num_workgroups) num_workgroups: vec3u,
+) {
+    let tile = tile_idx(wg_id, num_workgroups);
+
+    var total = identity();
+    for (var k = 0u; k < ITEMS_PER_THREAD; k += 1u) {
+        let idx = tile * TILE_LEN + k * WG_LEN + invocation.local_idx;
+        if idx < arrayLength(&input) {
+            total = combine(total, to_elem(input[idx], idx));
+        }
+    }
+
+    reduce_tile(total, invocation, tile);
+}
+
+@compute @workgroup_size(WG_LEN)
+fn main_partials(
+    invocation: ScanInvocation,
+    @builtin(workgroup_id) wg_id: vec3u,
+    @builtin(num_workgroups) num_workgroups: vec3u,
+) {
+    let tile = tile_idx(wg_id, num_workgroups);
+
+    var total = identity();
+    for (var k = 0u; k < ITEMS_PER_THREAD; k += 1u) {
+        let idx = tile * TILE_LEN + k * WG_LEN + invocation.local_idx;
+        if idx < arrayLength(&partials) {
+            total = combine(total, partials[idx]);
+        }
+    }
+
+    reduce_tile(total, invocation, tile);
+}
@@ -0,0 +1,104 @@
+// Value, identity_value() and combine_values() are prepended for the element type by
+// Engine::summed_area_table_inner
+
+struct Params {
+    // of the scanned axis
+    len: u32,
+    // elements between neighbors along the axis
+    stride: u32,
+    // elements of a line scanned by one thread
+    chunk_len: u32,
+    // per line, len.div_ceil(chunk_len)
+    chunks: u32,
+    // elements of the binding before the grid, which starts at an aligned offset
+    head: u32,
+    lines: u32,
+}
+
+@group(0) @binding(0)
+var<uniform> params: Params;
+
+@group(0) @binding(1)
+var<storage, read_write> buf: array<Value>;
+
+// the chunk totals of each line, contiguous per line
+@group(0) @binding(2)
+var<storage, read_write> totals: array<Value>;
+
+const WG_SIZE: u32 = 256;
+
+struct Chunk {
+    // index of the line's first element
+    first: u32,
+    // index of the chunk's total
+    total: u32,
+    begin: u32,
+    end: u32,
+}
+
+// neighboring threads take the same chunk of neighboring lines, which are neighbors in memory
+// along every axis but x
+fn chunk_at(global_id: vec3u, num_workgroups: vec3u) -> Chunk {
+    let idx = global_id.y * num_workgroups.x * WG_SIZE + global_id.x;
+    let line = idx % params.lines;
+    let chunk = idx / params.lines;
+
+    var c: Chunk;
+    c.first = params.head + line / params.stride * params.stride * params.len + line % params.stride;
+    c.total = line * params.chunks + chunk;
+    c.begin = chunk * params.chunk_len;
+    c.end = min(c.begin + params.chunk_len, params.len);
+    if chunk >= params.chunks {
+        c.end = c.begin;
+    }
+    return c;
+}
+
+fn scan_chunk(c: Chunk) -> Value {
+    var sum = identity_value();
+    for (var k = c.begin; k < c.end; k += 1u) {
+        let idx = c.first + k * params.stride;
+        sum = combine_values(sum, buf[idx]);
+        buf[idx] = sum;
+    }
+    return sum;
+}
+
+// lines of a single chunk, without totals
+@compute @workgroup_size(WG_SIZE)
+fn main(
+    @builtin(global_invocation_id) global_id: vec3u,
+    @builtin(num_workgroups) num_workgroups: vec3u,
+) {
+    scan_chunk(chunk_at(global_id, num_workgroups));
+}
+
+@compute @workgroup_size(WG_SIZE)
+fn main_chunks(
+    @builtin(global_invocation_id) global_id: vec3u,
+    @builtin(num_workgroups) num_workgroups: vec3u,
+) {
+    let c = chunk_at(global_id, num_workgroups);
+    if c.begin < c.end {
+        totals[c.total] = scan_chunk(c);
+    }
+}
+
+// after the totals have been scanned, every chunk but the first gets the total of the
+// chunks before it
+@compute @workgroup_size(WG_SIZE)
+fn main_carries(
+    @builtin(global_invocation_id) global_id: vec3u,
+    @builtin(num_workgroups) num_workgroups: vec3u,
+) {
+    let c = chunk_at(global_id, num_workgroups);
+    if c.begin == 0u || c.begin >= c.end {
+        return;
+    }
+
+    let carry = totals[c.total - 1u];
+    for (var k = c.begin; k < c.end; k += 1u) {
+        let idx = c.first + k * params.stride;
+        buf[idx] = combine_values(carry, buf[idx]);
+    }
+}
@@ -0,0 +1,52 @@
+// shared by the scan kernels, after the Elem declarations and before one of
+// scan_workgroup_shared.wgsl or scan_workgroup_subgroup.wgsl
+
+const WG_LEN: u32 = 256;
+// must match TILE_LEN in prefix_sum.rs
+const ITEMS_PER_THREAD: u32 = 8;
+const TILE_LEN: u32 = WG_LEN * ITEMS_PER_THREAD;
+
+var<workgroup> scratchpad: array<Elem, WG_LEN>;
+var<workgroup> workgroup_total: Elem;
+
+// each thread scans a contiguous run of the tile in registers
+fn scan_items(items: ptr<function, array<Elem, ITEMS_PER_THREAD>>) -> Elem {
+    for (var k = 1u; k < ITEMS_PER_THREAD; k += 1u) {
+        (*items)[k] = combine((*items)[k - 1u], (*items)[k]);
+    }
+    return (*items)[ITEMS_PER_THREAD - 1u];
+}
+
+// exclusive scan of the threads' totals with an up-sweep and a down-sweep over a balanced tree,
+// taking O(WG_LEN) operations and one barrier per level. The total of the workgroup is left in
+// workgroup_total.
+fn scan_threads(total: Elem, local_idx: u32) -> Elem {
+    scratchpad[local_idx] = total;
+
+    for (var stride = 1u; stride < WG_LEN; stride <<= 1u) {
+        workgroupBarrier();
+        let i = (local_idx + 1u) * 2u * stride - 1u;
+        if i < WG_LEN {
+            scratchpad[i] = combine(scratchpad[i - stride], scratchpad[i]);
+        }
+    }
+    workgroupBarrier();
+
+    if local_idx == 0u {
+        workgroup_total = scratchpad[WG_LEN - 1u];
+        scratchpad[WG_LEN - 1u] = identity();
+    }
+
+    for (var stride = WG_LEN / 2u; stride > 0u; stride >>= 1u) {
+        workgroupBarrier();
+        let i = (local_idx + 1u) * 2u * stride - 1u;
+        if i < WG_LEN {
+            let left = scratchpad[i - stride];
+            scratchpad[i - stride] = scratchpad[i];
+            scratchpad[i] = combine(scratchpad[i], left);
+        }
+    }
+    workgroupBarrier();
+
+    return scratchpad[local_idx];
+}
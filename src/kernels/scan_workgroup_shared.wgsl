@@ -0,0 +1,9 @@
+// the workgroup scan used without subgroup operations
+
+struct ScanInvocation {
+    @builtin(local_invocation_index) local_idx: u32,
+}
+
+fn scan_workgroup(total: Elem, invocation: ScanInvocation) -> Elem {
+    return scan_threads(total, invocation.local_idx);
+}
@@ -0,0 +1,56 @@
+// the workgroup scan used when the device supports subgroup operations, which needs
+// subgroup_scan() and shuffle_up() from the Elem declarations
+
+struct ScanInvocation {
+    @builtin(local_invocation_index) local_idx: u32,
+    @builtin(subgroup_invocation_id) lane: u32,
+    @builtin(subgroup_size) subgroup_size: u32,
+}
+
+// set by the threads whose subgroup and lane don't follow from their local index
+var<workgroup> lanes_scattered: u32;
+
+// exclusive scan of the threads' totals, within each subgroup and then across the subgroup
+// totals. The total of the workgroup is left in workgroup_total.
+//
+// The threads are combined in the order of their local index, which WGSL doesn't guarantee to be
+// the order of the subgroups and lanes. Workgroups where it isn't, or whose subgroups don't evenly
+// divide it, fall back to scan_threads().
+fn scan_workgroup(total: Elem, invocation: ScanInvocation) -> Elem {
+    let local_idx = invocation.local_idx;
+    let subgroup_size = invocation.subgroup_size;
+    // the subgroup holds the run of local indices starting at a multiple of its size
+    let subgroup_start = subgroupBroadcast(local_idx, 0u);
+    if WG_LEN % subgroup_size != 0u || subgroup_start != local_idx - invocation.lane || subgroup_start % subgroup_size != 0u {
+        lanes_scattered = 1u;
+    }
+    if workgroupUniformLoad(&lanes_scattered) != 0u {
+        return scan_threads(total, local_idx);
+    }
+
+    let sum = subgroup_scan(total, invocation.lane, subgroup_size);
+    var exclusive = shuffle_up(sum, 1u);
+    if invocation.lane == 0u {
+        exclusive = identity();
+    }
+
+    let slot = local_idx / subgroup_size;
+    if invocation.lane == subgroup_size - 1u {
+        scratchpad[slot] = sum;
+    }
+    workgroupBarrier();
+
+    // there are only a few subgroups, WG_LEN / 32 on most devices
+    if local_idx == 0u {
+        var acc = identity();
+        for (var i = 0u; i < WG_LEN / subgroup_size; i += 1u) {
+            let subgroup_total = scratchpad[i];
+            scratchpad[i] = acc;
+            acc = combine(acc, subgroup_total);
+        }
+        workgroup_total = acc;
+    }
+    workgroupBarrier();
+
+    return combine(scratchpad[slot], exclusive);
+}
@@ -0,0 +1,100 @@
+// Value, identity_value(), combine_values() and the segmented Elem, identity() and combine() are
+// prepended for the element type and operator by Engine::segmented_scan_inner
+
+@group(0) @binding(0)
+var<storage, read_write> values: array<Value>;
+
+// a nonzero flag at the start of every segment, or the sorted start offsets of the segments
+@group(0) @binding(1)
+var<storage, read> segments: array<u32>;
+
+@group(0) @binding(2)
+var<storage, read_write> pairs: array<Elem>;
+
+fn head_flag(idx: u32) -> bool {
+    return segments[idx] != 0u;
+}
+
+fn head_offset(idx: u32) -> bool {
+    // lower bound of idx in the offsets
+    var low = 0u;
+    var high = arrayLength(&segments);
+    while low < high {
+        let mid = (low + high) / 2u;
+        if segments[mid] < idx {
+            low = mid + 1u;
+        } else {
+            high = mid;
+        }
+    }
+    return low < arrayLength(&segments) && segments[low] == idx;
+}
+
+fn exclusive_value(idx: u32, head: bool) -> Value {
+    if idx == 0u || head {
+        return identity_value();
+    }
+    return pairs[idx - 1u].value;
+}
+
+const WG_SIZE: u32 = 256;
+
+// the dispatch is split along y when it has too many workgroups
+fn element_idx(global_id: vec3u, num_workgroups: vec3u) -> u32 {
+    return global_id.y * num_workgroups.x * WG_SIZE + global_id.x;
+}
+
+@compute @workgroup_size(WG_SIZE)
+fn pack_flags(
+    @builtin(global_invocation_id) global_id: vec3u,
+    @builtin(num_workgroups) num_workgroups: vec3u,
+) {
+    let idx = element_idx(global_id, num_workgroups);
+    if idx < arrayLength(&values) {
+        pairs[idx] = Elem(select(0u, 1u, head_flag(idx)), values[idx]);
+    }
+}
+
+@compute @workgroup_size(WG_SIZE)
+fn pack_offsets(
+    @builtin(global_invocation_id) global_id: vec3u,
+    @builtin(num_workgroups) num_workgroups: vec3u,
+) {
+    let idx = element_idx(global_id, num_workgroups);
+    if idx < arrayLength(&values) {
+        pairs[idx] = Elem(select(0u, 1u, head_offset(idx)), values[idx]);
+    }
+}
+
+@compute @workgroup_size(WG_SIZE)
+fn unpack(
+    @builtin(global_invocation_id) global_id: vec3u,
+    @builtin(num_workgroups) num_workgroups: vec3u,
+) {
+    let idx = element_idx(global_id, num_workgroups);
+    if idx < arrayLength(&values) {
+        values[idx] = pairs[idx].value;
+    }
+}
+
+@compute @workgroup_size(WG_SIZE)
+fn unpack_exclusive_flags(
+    @builtin(global_invocation_id) global_id: vec3u,
+    @builtin(num_workgroups) num_workgroups: vec3u,
+) {
+    let idx = element_idx(global_id, num_workgroups);
+    if idx < arrayLength(&values) {
+        values[idx] = exclusive_value(idx, head_flag(idx));
+    }
+}
+
+@compute @workgroup_size(WG_SIZE)
+fn unpack_exclusive_offsets(
+    @builtin(global_invocation_id) global_id: vec3u,
+    @builtin(num_workgroups) num_workgroups: vec3u,
+) {
+    let idx = element_idx(global_id, num_workgroups);
+    if idx < arrayLength(&values) {
+        values[idx] = exclusive_value(idx, head_offset(idx));
+    }
+}
@@ -1,10 +1,34 @@
 mod prefix_sum;
+mod compact;
 mod fenns;
+mod histogram;
+mod neighbor_search;
+mod pipeline;
+mod radix_sort;
+mod reduce;
+mod scan_batch;
+mod segmented_scan;
 
-use std::{borrow::Cow, collections::HashMap};
+pub use compact::{Compacted, Predicate};
+pub use fenns::{FennsParams, OutOfBounds};
+pub use histogram::Bins;
+pub use neighbor_search::{Attribute, NeighborSearch, NeighborSearchBuilder};
+pub use pipeline::Pipeline;
+pub use prefix_sum::{BufferRange, ScanDirection, ScanElement, ScanKind, ScanOp, ScanStrategy};
+pub use scan_batch::ScanBatch;
+pub use segmented_scan::Segments;
+
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
 
 use anyhow::Context;
 
+/// Params and grid functions shared by the FENNS kernels.
+const FENNS_GRID: &str = include_str!("kernels/fenns_grid.wgsl");
+
 #[repr(C, align(16))]
 #[derive(Copy, Clone, PartialEq, PartialOrd, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct Vec3A {
@@ -31,10 +55,26 @@ impl std::fmt::Debug for Vec3A {
     }
 }
 
+/// What the device supports, as far as kernel selection is concerned.
+#[derive(Clone, Debug)]
+pub struct Capabilities {
+    /// Largest FENNS grid whose cell counts fit in workgroup memory.
+    pub fenns_shared_grid_size: u64,
+    /// Most bins a histogram can count in workgroup memory.
+    pub histogram_shared_bins: u64,
+    /// Whether the scan kernels can use subgroup operations rather than only workgroup memory.
+    pub subgroups: bool,
+}
+
 pub struct Engine {
     pub device: wgpu::Device,
     pub queue: wgpu::Queue,
     pub kernels: HashMap<String, wgpu::ShaderModule>,
+    /// Pipelines of the kernels, keyed by kernel, entry point and binding types.
+    pub pipelines: Mutex<HashMap<String, Arc<Pipeline>>>,
+    pub capabilities: Capabilities,
+    /// How scans run, [`ScanStrategy::MultiLevel`] unless changed.
+    pub scan_strategy: ScanStrategy,
 }
 
 impl Engine {
@@ -90,40 +130,45 @@ impl Engine {
         #[cfg(test)]
         println!("{:?}\n", adapter.get_info());
 
+        let subgroups = adapter.features().contains(wgpu::Features::SUBGROUP);
+        let mut required_features = wgpu::Features::TIMESTAMP_QUERY;
+        if subgroups {
+            required_features |= wgpu::Features::SUBGROUP;
+        }
+
         let (device, queue) = adapter
             .request_device(
                 &wgpu::DeviceDescriptor {
                     label: None,
-                    required_features: wgpu::Features::TIMESTAMP_QUERY,
-                    required_limits: Default::default(),
+                    required_features,
+                    // the shared cell counts of fenns_sort1 fill the workgroup memory
+                    required_limits: wgpu::Limits {
+                        max_compute_workgroup_storage_size: adapter.limits().max_compute_workgroup_storage_size,
+                        ..Default::default()
+                    },
                 },
                 None,
             )
             .await?;
 
-        let mut kernels = HashMap::new();
-
-        kernels.insert(
-            "psum1".into(),
-            device.create_shader_module(wgpu::ShaderModuleDescriptor {
-                label: Some("kernels/psum1.wgsl"),
-                source: wgpu::ShaderSource::Wgsl(Cow::Borrowed(include_str!("kernels/psum1.wgsl"))),
-            }),
-        );
+        let capabilities = Capabilities {
+            fenns_shared_grid_size: device.limits().max_compute_workgroup_storage_size as u64 / 8,
+            histogram_shared_bins: device.limits().max_compute_workgroup_storage_size as u64 / 4,
+            subgroups,
+        };
 
-        kernels.insert(
-            "psum2".into(),
-            device.create_shader_module(wgpu::ShaderModuleDescriptor {
-                label: Some("kernels/psum2.wgsl"),
-                source: wgpu::ShaderSource::Wgsl(Cow::Borrowed(include_str!("kernels/psum2.wgsl"))),
-            }),
-        );
+        let mut kernels = HashMap::new();
 
         kernels.insert(
             "fenns_sort1".into(),
             device.create_shader_module(wgpu::ShaderModuleDescriptor {
                 label: Some("kernels/fenns_sort1.wgsl"),
-                source: wgpu::ShaderSource::Wgsl(Cow::Borrowed(include_str!("kernels/fenns_sort1.wgsl"))),
+                source: wgpu::ShaderSource::Wgsl(Cow::Owned(format!(
+                    "const SHARED_COUNTS: u32 = {}u;\n{}\n{}",
+                    2 * capabilities.fenns_shared_grid_size,
+                    FENNS_GRID,
+                    include_str!("kernels/fenns_sort1.wgsl"),
+                ))),
             }),
         );
         
@@ -131,15 +176,31 @@ impl Engine {
             "fenns_sort2".into(),
             device.create_shader_module(wgpu::ShaderModuleDescriptor {
                 label: Some("kernels/fenns_sort2.wgsl"),
-                source: wgpu::ShaderSource::Wgsl(Cow::Borrowed(include_str!("kernels/fenns_sort2.wgsl"))),
+                source: wgpu::ShaderSource::Wgsl(Cow::Owned(format!(
+                    "{}\n{}",
+                    FENNS_GRID,
+                    include_str!("kernels/fenns_sort2.wgsl"),
+                ))),
             }),
         );
         
         kernels.insert(
-            "fenns_sort_shift".into(),
+            "fenns_gather".into(),
+            device.create_shader_module(wgpu::ShaderModuleDescriptor {
+                label: Some("kernels/fenns_gather.wgsl"),
+                source: wgpu::ShaderSource::Wgsl(Cow::Borrowed(include_str!("kernels/fenns_gather.wgsl"))),
+            }),
+        );
+
+        kernels.insert(
+            "fenns_search".into(),
             device.create_shader_module(wgpu::ShaderModuleDescriptor {
-                label: Some("kernels/fenns_sort_shift.wgsl"),
-                source: wgpu::ShaderSource::Wgsl(Cow::Borrowed(include_str!("kernels/fenns_sort_shift.wgsl"))),
+                label: Some("kernels/fenns_search.wgsl"),
+                source: wgpu::ShaderSource::Wgsl(Cow::Owned(format!(
+                    "{}\n{}",
+                    FENNS_GRID,
+                    include_str!("kernels/fenns_search.wgsl"),
+                ))),
             }),
         );
 
@@ -147,6 +208,9 @@ impl Engine {
             device,
             queue,
             kernels,
+            pipelines: Mutex::new(HashMap::new()),
+            capabilities,
+            scan_strategy: ScanStrategy::default(),
         })
     }
 }
@@ -163,16 +227,16 @@ mod tests {
         let start = pos.saturating_sub(30);
         let end = (pos + 30).min(a.len());
 
-        write!(
+        writeln!(
             msg,
-            "{: <10} | {: <45} | {: <45}\n",
+            "{: <10} | {: <45} | {: <45}",
             "idx", name_a, name_b,
         ).unwrap();
 
         for display_i in start..end {
-            write!(
+            writeln!(
                 msg,
-                "{: <10} | {: <45} | {: <45}\n",
+                "{: <10} | {: <45} | {: <45}",
                 display_i, format!("{:?}", a[display_i]), format!("{:?}", b[display_i])
             ).unwrap();
         }
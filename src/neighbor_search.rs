@@ -0,0 +1,934 @@
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::Context;
+use wgpu::util::DeviceExt;
+
+use crate::{Engine, FennsParams, OutOfBounds, ScanKind, Vec3A};
+
+impl Engine {
+    pub fn neighbor_search(&self) -> NeighborSearchBuilder<'_> {
+        NeighborSearchBuilder {
+            engine: self,
+            cell_width: 1.0,
+            search_radius: 1.0,
+            domain: None,
+            periodic: [false; 3],
+            table_size: None,
+            out_of_bounds: OutOfBounds::Error,
+            max_neighbors: 64,
+        }
+    }
+}
+
+pub struct NeighborSearchBuilder<'a> {
+    engine: &'a Engine,
+    cell_width: f32,
+    search_radius: f32,
+    domain: Option<([f32; 3], [f32; 3])>,
+    periodic: [bool; 3],
+    table_size: Option<u32>,
+    out_of_bounds: OutOfBounds,
+    max_neighbors: u32,
+}
+
+impl<'a> NeighborSearchBuilder<'a> {
+    pub fn cell_width(mut self, cell_width: f32) -> Self {
+        self.cell_width = cell_width;
+        self
+    }
+
+    /// Must not exceed the cell width, as only the 27 surrounding cells are searched.
+    pub fn search_radius(mut self, search_radius: f32) -> Self {
+        self.search_radius = search_radius;
+        self
+    }
+
+    /// Simulation box spanning `extent` from `origin`. The grid is sized to cover it.
+    pub fn domain(mut self, origin: [f32; 3], extent: [f32; 3]) -> Self {
+        self.domain = Some((origin, extent));
+        self
+    }
+
+    /// Axes along which the domain wraps around. Their extent must be a multiple of the cell
+    /// width and span at least three cells.
+    pub fn periodic(mut self, periodic: [bool; 3]) -> Self {
+        self.periodic = periodic;
+        self
+    }
+
+    /// Hashes the cells into a table of `table_size` slots instead of allocating a dense grid,
+    /// for sparse or unbounded scenes. The domain is then optional and only sets the origin, and
+    /// no particle is ever out of bounds. Periodic axes are not supported.
+    pub fn hash_table(mut self, table_size: u32) -> Self {
+        self.table_size = Some(table_size);
+        self
+    }
+
+    /// What to do with particles outside of the grid, defaults to [`OutOfBounds::Error`].
+    pub fn out_of_bounds(mut self, out_of_bounds: OutOfBounds) -> Self {
+        self.out_of_bounds = out_of_bounds;
+        self
+    }
+
+    /// Neighbors stored per particle, [`NeighborSearch::query`] fails if any particle has more.
+    pub fn max_neighbors(mut self, max_neighbors: u32) -> Self {
+        self.max_neighbors = max_neighbors;
+        self
+    }
+
+    pub fn init(self) -> anyhow::Result<NeighborSearch<'a>> {
+        anyhow::ensure!(self.cell_width > 0.0, "cell width must be positive");
+        anyhow::ensure!(self.search_radius > 0.0, "search radius must be positive");
+        anyhow::ensure!(
+            self.search_radius <= self.cell_width,
+            "search radius ({}) must not exceed the cell width ({})",
+            self.search_radius,
+            self.cell_width,
+        );
+        anyhow::ensure!(self.max_neighbors > 0, "max_neighbors must be positive");
+
+        let params = match self.table_size {
+            Some(table_size) => self.hashed_params(table_size)?,
+            None => self.grid_params()?,
+        };
+
+        // the neighbors of even a single particle have to fit in a binding
+        let max_size = max_storage_size(self.engine);
+        anyhow::ensure!(
+            self.max_neighbors as u64 * 4 <= max_size,
+            "max_neighbors ({}) exceeds the {} bytes a neighbor buffer may take",
+            self.max_neighbors,
+            max_size,
+        );
+
+        let max_grid_size = self.engine.device.limits().max_storage_buffer_binding_size as u64 / 8;
+        anyhow::ensure!(
+            params.grid_size() <= max_grid_size,
+            "a grid of {} cells is larger than the {} supported by the device",
+            params.grid_size(),
+            max_grid_size,
+        );
+
+        let params_buf = self
+            .engine
+            .device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("neighbor_search/params"),
+                contents: bytemuck::bytes_of(&params),
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            });
+
+        let count_buf = self.engine.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("neighbor_search/count"),
+            size: params.count_buffer_size(),
+            mapped_at_creation: false,
+            usage: wgpu::BufferUsages::STORAGE
+                | wgpu::BufferUsages::COPY_DST
+                | wgpu::BufferUsages::COPY_SRC,
+        });
+
+        let strays_buf = self.engine.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("neighbor_search/strays"),
+            size: 4 * 2,
+            mapped_at_creation: false,
+            usage: wgpu::BufferUsages::STORAGE
+                | wgpu::BufferUsages::COPY_DST
+                | wgpu::BufferUsages::COPY_SRC,
+        });
+
+        Ok(NeighborSearch {
+            engine: self.engine,
+            out_of_bounds: self.out_of_bounds,
+            max_neighbors: self.max_neighbors,
+            params_buf,
+            count_buf,
+            strays_buf,
+            particle_bufs: None,
+            id: NEXT_SEARCH_ID.fetch_add(1, Ordering::Relaxed),
+            attributes: Vec::new(),
+        })
+    }
+
+    fn grid_params(&self) -> anyhow::Result<FennsParams> {
+        let (origin, extent) = self.domain.context("neighbor search domain must be set")?;
+        anyhow::ensure!(
+            extent.iter().all(|&extent| extent > 0.0),
+            "domain extent {:?} must be positive",
+            extent,
+        );
+
+        let mut grid_dim = extent.map(|extent| (extent / self.cell_width).ceil() as u32);
+        for axis in (0..3).filter(|&axis| self.periodic[axis]) {
+            let cells = extent[axis] / self.cell_width;
+            anyhow::ensure!(
+                (cells - cells.round()).abs() <= 1e-4 * cells,
+                "periodic extent {} must be a multiple of the cell width ({})",
+                extent[axis],
+                self.cell_width,
+            );
+            grid_dim[axis] = cells.round() as u32;
+            anyhow::ensure!(
+                grid_dim[axis] >= 3,
+                "periodic extent {} must span at least 3 cells",
+                extent[axis],
+            );
+        }
+
+        Ok(FennsParams::new(grid_dim, self.cell_width, self.search_radius)
+            .with_origin(origin)
+            .with_periodic(self.periodic)
+            .with_out_of_bounds(self.out_of_bounds))
+    }
+
+    fn hashed_params(&self, table_size: u32) -> anyhow::Result<FennsParams> {
+        anyhow::ensure!(table_size > 0, "hash table size must be positive");
+        anyhow::ensure!(
+            !self.periodic.contains(&true),
+            "periodic axes are not supported with a hash table",
+        );
+
+        let origin = self.domain.map_or([0.0; 3], |(origin, _)| origin);
+        Ok(FennsParams::new([1; 3], self.cell_width, self.search_radius)
+            .with_origin(origin)
+            .with_hash_table(table_size))
+    }
+}
+
+struct ParticleBuffers {
+    len: usize,
+    particles: wgpu::Buffer,
+    reordered: wgpu::Buffer,
+    order: wgpu::Buffer,
+    inverse_order: wgpu::Buffer,
+    neighbor_count: wgpu::Buffer,
+    neighbors: wgpu::Buffer,
+}
+
+struct AttributeBuffers {
+    len: usize,
+    values: wgpu::Buffer,
+    reordered: wgpu::Buffer,
+}
+
+/// Tells the searches apart, so that their attributes can't be mixed up.
+static NEXT_SEARCH_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Per-particle attribute registered with [`NeighborSearch::add_attribute`], only valid for the
+/// search it was added to.
+pub struct Attribute<T> {
+    search_id: u64,
+    idx: usize,
+    _marker: PhantomData<T>,
+}
+
+impl<T> Clone for Attribute<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Attribute<T> {}
+
+/// Owns the buffers of the FENNS pipeline and runs it on a set of particles.
+///
+/// Neighbor indices refer to the cell-sorted order returned by [`NeighborSearch::sorted_particles`],
+/// [`NeighborSearch::order`] maps them back to the original particles.
+/// With [`OutOfBounds::Drop`], the particles outside of the grid come last and have no neighbors.
+///
+/// Attributes added with [`NeighborSearch::add_attribute`] are reordered along with the particles
+/// on every (re)build.
+pub struct NeighborSearch<'a> {
+    engine: &'a Engine,
+    out_of_bounds: OutOfBounds,
+    max_neighbors: u32,
+    params_buf: wgpu::Buffer,
+    count_buf: wgpu::Buffer,
+    strays_buf: wgpu::Buffer,
+    particle_bufs: Option<ParticleBuffers>,
+    id: u64,
+    attributes: Vec<AttributeBuffers>,
+}
+
+impl<'a> NeighborSearch<'a> {
+    /// Uploads `particles` and sorts them into the grid, reusing the buffers if the particle
+    /// count didn't change since the last call.
+    pub async fn build(&mut self, particles: &[Vec3A]) -> anyhow::Result<()> {
+        anyhow::ensure!(!particles.is_empty(), "neighbor search needs at least one particle");
+
+        match &self.particle_bufs {
+            Some(bufs) if bufs.len == particles.len() => {
+                self.engine
+                    .queue
+                    .write_buffer(&bufs.particles, 0, bytemuck::cast_slice(particles));
+            }
+            _ => self.particle_bufs = Some(self.create_particle_buffers(particles)?),
+        }
+
+        self.rebuild().await
+    }
+
+    /// Sorts the current contents of the particle buffer into the grid again, e.g. after they
+    /// were moved by another kernel.
+    ///
+    /// Only waits on the GPU when particles outside of the grid are treated as an error.
+    pub async fn rebuild(&self) -> anyhow::Result<()> {
+        let bufs = self.particle_bufs()?;
+        for (i, attribute) in self.attributes.iter().enumerate() {
+            anyhow::ensure!(
+                attribute.len == bufs.len,
+                "attribute #{} has {} values for {} particles",
+                i,
+                attribute.len,
+                bufs.len,
+            );
+        }
+
+        let mut encoder = self.engine.device.create_command_encoder(&Default::default());
+        encoder.clear_buffer(&self.count_buf, 0, None);
+        encoder.clear_buffer(&self.strays_buf, 0, None);
+        self.engine.queue.submit(Some(encoder.finish()));
+
+        self.engine.fenns_sort1(&[
+            &self.params_buf,
+            &bufs.particles,
+            &self.count_buf,
+            &self.strays_buf,
+        ]);
+        self.engine.prefix_sum_inner::<u32>(&self.count_buf, ScanKind::Exclusive);
+        self.engine.fenns_sort2(&[
+            &self.params_buf,
+            &bufs.particles,
+            &self.count_buf,
+            &bufs.reordered,
+            &self.strays_buf,
+            &bufs.order,
+            &bufs.inverse_order,
+        ]);
+
+        for attribute in &self.attributes {
+            self.engine
+                .fenns_gather(&[&bufs.order, &attribute.values, &attribute.reordered]);
+        }
+
+        if self.out_of_bounds == OutOfBounds::Error {
+            let stray_count = self.stray_count().await?;
+            anyhow::ensure!(stray_count == 0, "{} particles lie outside of the grid", stray_count);
+        }
+
+        Ok(())
+    }
+
+    /// Registers a per-particle attribute, one value per particle in their original order.
+    ///
+    /// The size of `T` must be a multiple of 4 bytes.
+    pub fn add_attribute<T: bytemuck::Pod>(&mut self, values: &[T]) -> anyhow::Result<Attribute<T>> {
+        let attribute = Attribute {
+            search_id: self.id,
+            idx: self.attributes.len(),
+            _marker: PhantomData,
+        };
+        self.attributes.push(self.create_attribute_buffers(values)?);
+        Ok(attribute)
+    }
+
+    /// Replaces the values of an attribute, they are reordered on the next (re)build.
+    pub fn write_attribute<T: bytemuck::Pod>(&mut self, attribute: Attribute<T>, values: &[T]) -> anyhow::Result<()> {
+        let buffers = self.attribute_buffers(attribute)?;
+        if buffers.len == values.len() {
+            self.engine.queue.write_buffer(&buffers.values, 0, bytemuck::cast_slice(values));
+        } else {
+            self.attributes[attribute.idx] = self.create_attribute_buffers(values)?;
+        }
+
+        Ok(())
+    }
+
+    /// Values of an attribute in cell-sorted order, as of the last (re)build.
+    pub async fn sorted_attribute<T: bytemuck::Pod>(&self, attribute: Attribute<T>) -> anyhow::Result<Vec<T>> {
+        self.engine
+            .map_buffer(&self.attribute_buffers(attribute)?.reordered)
+            .await
+    }
+
+    pub fn attribute_buffer<T>(&self, attribute: Attribute<T>) -> anyhow::Result<&wgpu::Buffer> {
+        Ok(&self.attribute_buffers(attribute)?.values)
+    }
+
+    pub fn sorted_attribute_buffer<T>(&self, attribute: Attribute<T>) -> anyhow::Result<&wgpu::Buffer> {
+        Ok(&self.attribute_buffers(attribute)?.reordered)
+    }
+
+    fn attribute_buffers<T>(&self, attribute: Attribute<T>) -> anyhow::Result<&AttributeBuffers> {
+        anyhow::ensure!(attribute.search_id == self.id, "attribute was added to another neighbor search");
+        Ok(&self.attributes[attribute.idx])
+    }
+
+    /// Number of particles that were outside of the grid during the last (re)build, whether
+    /// they were clamped or dropped.
+    pub async fn stray_count(&self) -> anyhow::Result<u32> {
+        let strays: Vec<u32> = self.engine.map_buffer(&self.strays_buf).await?;
+        Ok(strays[0])
+    }
+
+    /// Returns the neighbors of every particle, in cell-sorted order.
+    pub async fn query(&self) -> anyhow::Result<Vec<Vec<u32>>> {
+        self.query_inner()?;
+
+        let bufs = self.particle_bufs()?;
+        let neighbor_counts: Vec<u32> = self.engine.map_buffer(&bufs.neighbor_count).await?;
+        let neighbors: Vec<u32> = self.engine.map_buffer(&bufs.neighbors).await?;
+
+        std::iter::zip(neighbor_counts, neighbors.chunks(self.max_neighbors as usize))
+            .enumerate()
+            .map(|(i, (count, neighbors))| {
+                anyhow::ensure!(
+                    count <= self.max_neighbors,
+                    "particle #{} has {} neighbors, more than max_neighbors ({})",
+                    i,
+                    count,
+                    self.max_neighbors,
+                );
+                Ok(neighbors[..count as usize].to_vec())
+            })
+            .collect()
+    }
+
+    /// Runs the search without reading back the results, which are left in
+    /// [`NeighborSearch::neighbor_count_buffer`] and [`NeighborSearch::neighbors_buffer`].
+    pub fn query_inner(&self) -> anyhow::Result<()> {
+        let bufs = self.particle_bufs()?;
+
+        self.engine.fenns_search(&[
+            &self.params_buf,
+            &bufs.reordered,
+            &self.count_buf,
+            &bufs.neighbor_count,
+            &bufs.neighbors,
+        ]);
+
+        Ok(())
+    }
+
+    pub async fn sorted_particles(&self) -> anyhow::Result<Vec<Vec3A>> {
+        self.engine.map_buffer(&self.particle_bufs()?.reordered).await
+    }
+
+    /// Original index of each cell-sorted particle, `sorted[i] == particles[order[i]]`.
+    pub async fn order(&self) -> anyhow::Result<Vec<u32>> {
+        self.engine.map_buffer(&self.particle_bufs()?.order).await
+    }
+
+    /// Cell-sorted index of each original particle, the inverse of [`NeighborSearch::order`].
+    pub async fn inverse_order(&self) -> anyhow::Result<Vec<u32>> {
+        self.engine.map_buffer(&self.particle_bufs()?.inverse_order).await
+    }
+
+    pub fn particles_buffer(&self) -> Option<&wgpu::Buffer> {
+        self.particle_bufs.as_ref().map(|bufs| &bufs.particles)
+    }
+
+    pub fn sorted_particles_buffer(&self) -> Option<&wgpu::Buffer> {
+        self.particle_bufs.as_ref().map(|bufs| &bufs.reordered)
+    }
+
+    pub fn order_buffer(&self) -> Option<&wgpu::Buffer> {
+        self.particle_bufs.as_ref().map(|bufs| &bufs.order)
+    }
+
+    pub fn inverse_order_buffer(&self) -> Option<&wgpu::Buffer> {
+        self.particle_bufs.as_ref().map(|bufs| &bufs.inverse_order)
+    }
+
+    pub fn neighbor_count_buffer(&self) -> Option<&wgpu::Buffer> {
+        self.particle_bufs.as_ref().map(|bufs| &bufs.neighbor_count)
+    }
+
+    /// `max_neighbors` slots per particle, only the first `neighbor_count` of which are valid.
+    pub fn neighbors_buffer(&self) -> Option<&wgpu::Buffer> {
+        self.particle_bufs.as_ref().map(|bufs| &bufs.neighbors)
+    }
+
+    fn particle_bufs(&self) -> anyhow::Result<&ParticleBuffers> {
+        self.particle_bufs
+            .as_ref()
+            .context("NeighborSearch::build must be called first")
+    }
+
+    fn create_attribute_buffers<T: bytemuck::Pod>(&self, values: &[T]) -> anyhow::Result<AttributeBuffers> {
+        let size = std::mem::size_of::<T>();
+        anyhow::ensure!(
+            size > 0 && size.is_multiple_of(4),
+            "attribute size ({} bytes) must be a positive multiple of 4",
+            size,
+        );
+        anyhow::ensure!(!values.is_empty(), "attribute needs at least one value");
+
+        let device = &self.engine.device;
+
+        let values_buf = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("neighbor_search/attribute"),
+            contents: bytemuck::cast_slice(values),
+            usage: wgpu::BufferUsages::STORAGE
+                | wgpu::BufferUsages::COPY_DST
+                | wgpu::BufferUsages::COPY_SRC,
+        });
+
+        let reordered = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("neighbor_search/reordered_attribute"),
+            size: values_buf.size(),
+            mapped_at_creation: false,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+        });
+
+        Ok(AttributeBuffers {
+            len: values.len(),
+            values: values_buf,
+            reordered,
+        })
+    }
+
+    fn create_particle_buffers(&self, particles: &[Vec3A]) -> anyhow::Result<ParticleBuffers> {
+        let device = &self.engine.device;
+        let len = particles.len() as u64;
+
+        // the kernels bind the particles and their neighbors whole
+        let max_size = max_storage_size(self.engine);
+        anyhow::ensure!(
+            len * 16 <= max_size && len * self.max_neighbors as u64 * 4 <= max_size,
+            "{} particles with up to {} neighbors each exceed the {} bytes a buffer may take",
+            len,
+            self.max_neighbors,
+            max_size,
+        );
+
+        let particles_buf = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("neighbor_search/particles"),
+            contents: bytemuck::cast_slice(particles),
+            usage: wgpu::BufferUsages::STORAGE
+                | wgpu::BufferUsages::COPY_DST
+                | wgpu::BufferUsages::COPY_SRC,
+        });
+
+        let reordered = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("neighbor_search/reordered"),
+            size: len * 16,
+            mapped_at_creation: false,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+        });
+
+        let order = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("neighbor_search/order"),
+            size: len * 4,
+            mapped_at_creation: false,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+        });
+
+        let inverse_order = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("neighbor_search/inverse_order"),
+            size: len * 4,
+            mapped_at_creation: false,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+        });
+
+        let neighbor_count = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("neighbor_search/neighbor_count"),
+            size: len * 4,
+            mapped_at_creation: false,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+        });
+
+        let neighbors = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("neighbor_search/neighbors"),
+            size: len * self.max_neighbors as u64 * 4,
+            mapped_at_creation: false,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+        });
+
+        Ok(ParticleBuffers {
+            len: particles.len(),
+            particles: particles_buf,
+            reordered,
+            order,
+            inverse_order,
+            neighbor_count,
+            neighbors,
+        })
+    }
+}
+
+/// The largest buffer the search kernels can bind whole.
+fn max_storage_size(engine: &Engine) -> u64 {
+    let limits = engine.device.limits();
+    (limits.max_storage_buffer_binding_size as u64).min(limits.max_buffer_size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fenns::tests::{gen_clustered_particles, neighbors_cpu, neighbors_cpu_periodic};
+    use crate::tests::assert_slices_eq;
+
+    #[tokio::test]
+    async fn neighbor_search_matches_cpu() -> anyhow::Result<()> {
+        let engine = Engine::new().await?;
+
+        const SEARCH_RADIUS: f32 = 0.5;
+        let mut search = engine
+            .neighbor_search()
+            .cell_width(1.0)
+            .search_radius(SEARCH_RADIUS)
+            .domain([0.0; 3], [16.0, 8.5, 17.0])
+            .max_neighbors(128)
+            .init()?;
+
+        // the second build reuses the buffers of the first one
+        for seed in 0..3 {
+            println!("Seed: {}", seed);
+            let particles = gen_clustered_particles(seed, 3000, &[[2.0, 3.5, 0.0], [11.0, 3.0, 12.0]], 5.0);
+            search.build(&particles).await?;
+
+            let sorted = search.sorted_particles().await?;
+            let order = search.order().await?;
+            let inverse_order = search.inverse_order().await?;
+            for (i, &original) in order.iter().enumerate() {
+                assert_eq!(sorted[i], particles[original as usize]);
+                assert_eq!(inverse_order[original as usize], i as u32);
+            }
+
+            let neighbors = search.query().await?;
+            let expected = neighbors_cpu(&sorted, SEARCH_RADIUS);
+
+            assert_eq!(neighbors.len(), expected.len());
+            for (mut found, expected) in std::iter::zip(neighbors, expected) {
+                found.sort();
+                assert_slices_eq(&found, &expected);
+            }
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn neighbor_search_large_grid() -> anyhow::Result<()> {
+        let engine = Engine::new().await?;
+
+        const SEARCH_RADIUS: f32 = 0.25;
+        let mut search = engine
+            .neighbor_search()
+            .cell_width(0.25)
+            .search_radius(SEARCH_RADIUS)
+            .domain([0.0; 3], [40.0, 30.0, 25.0])
+            .init()?;
+
+        let particles = gen_clustered_particles(0, 2000, &[[0.0, 0.0, 0.0], [20.0, 12.0, 8.0], [37.0, 27.0, 22.0]], 3.0);
+        search.build(&particles).await?;
+
+        let sorted = search.sorted_particles().await?;
+        let neighbors = search.query().await?;
+        let expected = neighbors_cpu(&sorted, SEARCH_RADIUS);
+
+        for (mut found, expected) in std::iter::zip(neighbors, expected) {
+            found.sort();
+            assert_slices_eq(&found, &expected);
+        }
+
+        Ok(())
+    }
+
+    // more particles than a single row of 65535 workgroups of 64 threads
+    #[tokio::test]
+    async fn neighbor_search_many_particles() -> anyhow::Result<()> {
+        let engine = Engine::new().await?;
+
+        // the axis neighbors on a lattice 0.5 apart, the diagonal ones are too far
+        const DIM: [usize; 3] = [128, 128, 256];
+        let mut search = engine
+            .neighbor_search()
+            .cell_width(1.0)
+            .search_radius(0.6)
+            .domain([0.0; 3], [64.0, 64.0, 128.0])
+            .max_neighbors(8)
+            .init()?;
+
+        let particles: Vec<Vec3A> = (0..DIM[0] * DIM[1] * DIM[2])
+            .map(|i| {
+                let (x, y, z) = (i % DIM[0], i / DIM[0] % DIM[1], i / (DIM[0] * DIM[1]));
+                Vec3A::new(x as f32 * 0.5, y as f32 * 0.5, z as f32 * 0.5)
+            })
+            .collect();
+        assert!(particles.len() > 65535 * 64);
+        search.build(&particles).await?;
+
+        let sorted = search.sorted_particles().await?;
+        let order = search.order().await?;
+        for (i, particle) in sorted.iter().enumerate() {
+            assert_eq!(particles[order[i] as usize], *particle);
+        }
+
+        let neighbors = search.query().await?;
+        for (particle, neighbors) in std::iter::zip(&sorted, neighbors) {
+            let lattice_pos = [particle.x, particle.y, particle.z].map(|coord| (coord * 2.0) as usize);
+            let expected: usize = (0..3).map(|axis| (lattice_pos[axis] > 0) as usize + (lattice_pos[axis] + 1 < DIM[axis]) as usize).sum();
+            assert_eq!(neighbors.len(), expected, "at {:?}", lattice_pos);
+        }
+
+        Ok(())
+    }
+
+    const STRAY_ORIGIN: [f32; 3] = [-5.0, -3.0, -4.0];
+    const STRAY_EXTENT: [f32; 3] = [10.0, 6.0, 8.0];
+
+    // clusters straddling the lower and upper faces of the domain
+    fn gen_stray_particles() -> Vec<Vec3A> {
+        gen_clustered_particles(1, 2000, &[[-6.0, -4.0, -5.0], [3.5, 1.5, 2.5]], 3.0)
+    }
+
+    fn is_stray(particle: &Vec3A) -> bool {
+        let coords = [particle.x, particle.y, particle.z];
+        (0..3).any(|axis| !(STRAY_ORIGIN[axis]..STRAY_ORIGIN[axis] + STRAY_EXTENT[axis]).contains(&coords[axis]))
+    }
+
+    #[tokio::test]
+    async fn neighbor_search_clamps_strays() -> anyhow::Result<()> {
+        let engine = Engine::new().await?;
+
+        const SEARCH_RADIUS: f32 = 0.5;
+        let mut search = engine
+            .neighbor_search()
+            .search_radius(SEARCH_RADIUS)
+            .domain(STRAY_ORIGIN, STRAY_EXTENT)
+            .out_of_bounds(OutOfBounds::Clamp)
+            .max_neighbors(256)
+            .init()?;
+
+        let particles = gen_stray_particles();
+        search.build(&particles).await?;
+
+        let stray_count = particles.iter().filter(|p| is_stray(p)).count();
+        assert!(stray_count > 0);
+        assert_eq!(search.stray_count().await?, stray_count as u32);
+
+        let sorted = search.sorted_particles().await?;
+        let neighbors = search.query().await?;
+        let expected = neighbors_cpu(&sorted, SEARCH_RADIUS);
+
+        for (mut found, expected) in std::iter::zip(neighbors, expected) {
+            found.sort();
+            assert_slices_eq(&found, &expected);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn neighbor_search_drops_strays() -> anyhow::Result<()> {
+        let engine = Engine::new().await?;
+
+        const SEARCH_RADIUS: f32 = 0.5;
+        let mut search = engine
+            .neighbor_search()
+            .search_radius(SEARCH_RADIUS)
+            .domain(STRAY_ORIGIN, STRAY_EXTENT)
+            .out_of_bounds(OutOfBounds::Drop)
+            .max_neighbors(256)
+            .init()?;
+
+        let particles = gen_stray_particles();
+        search.build(&particles).await?;
+
+        let stray_count = particles.iter().filter(|p| is_stray(p)).count();
+        assert_eq!(search.stray_count().await?, stray_count as u32);
+
+        let sorted = search.sorted_particles().await?;
+        let (placed, strays) = sorted.split_at(sorted.len() - stray_count);
+        assert!(placed.iter().all(|p| !is_stray(p)));
+        assert!(strays.iter().all(is_stray));
+
+        let neighbors = search.query().await?;
+        let expected = neighbors_cpu(placed, SEARCH_RADIUS);
+
+        for (i, mut found) in neighbors.into_iter().enumerate() {
+            found.sort();
+            assert_slices_eq(&found, expected.get(i).map_or(&[], |n| &n[..]));
+        }
+
+        let mut search = engine
+            .neighbor_search()
+            .domain(STRAY_ORIGIN, STRAY_EXTENT)
+            .init()?;
+        assert!(search.build(&particles).await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn neighbor_search_periodic() -> anyhow::Result<()> {
+        let engine = Engine::new().await?;
+
+        const SEARCH_RADIUS: f32 = 0.5;
+        const ORIGIN: [f32; 3] = [-2.0, 0.0, 1.0];
+        const EXTENT: [f32; 3] = [6.0, 5.0, 4.0];
+        let mut search = engine
+            .neighbor_search()
+            .search_radius(SEARCH_RADIUS)
+            .domain(ORIGIN, EXTENT)
+            .periodic([true, false, true])
+            .max_neighbors(512)
+            .init()?;
+
+        // clusters straddling the periodic faces, one of them a whole period away from the box
+        let particles = gen_clustered_particles(
+            0,
+            1000,
+            &[[3.3, 1.0, 4.4], [-2.6, 3.0, 0.5], [9.5, 2.0, -6.8]],
+            1.5,
+        );
+        search.build(&particles).await?;
+        assert_eq!(search.stray_count().await?, 0);
+
+        let sorted = search.sorted_particles().await?;
+        let neighbors = search.query().await?;
+        let expected = neighbors_cpu_periodic(&sorted, SEARCH_RADIUS, [Some(EXTENT[0]), None, Some(EXTENT[2])]);
+
+        for (mut found, expected) in std::iter::zip(neighbors, expected) {
+            found.sort();
+            assert_slices_eq(&found, &expected);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn neighbor_search_hashed() -> anyhow::Result<()> {
+        let engine = Engine::new().await?;
+
+        const SEARCH_RADIUS: f32 = 0.5;
+        // far apart clusters without any domain, one of them sitting on cell faces at negative coordinates
+        let particles = gen_clustered_particles(
+            0,
+            1000,
+            &[[-1.5, -1.5, -1.5], [1000.0, 3.2, -250.7], [-3e4, 2e4, 17.0]],
+            3.0,
+        );
+        let expected = neighbors_cpu(&particles, SEARCH_RADIUS);
+
+        // a small table has plenty of collisions, including between neighboring cells
+        for table_size in [1, 7, 4096] {
+            println!("Table size: {}", table_size);
+            let mut search = engine
+                .neighbor_search()
+                .search_radius(SEARCH_RADIUS)
+                .hash_table(table_size)
+                .max_neighbors(128)
+                .init()?;
+
+            search.build(&particles).await?;
+            assert_eq!(search.stray_count().await?, 0);
+
+            let order = search.order().await?;
+            let neighbors = search.query().await?;
+
+            for (i, found) in neighbors.into_iter().enumerate() {
+                let mut found: Vec<u32> = found.into_iter().map(|j| order[j as usize]).collect();
+                found.sort();
+                assert_slices_eq(&found, &expected[order[i] as usize]);
+            }
+        }
+
+        Ok(())
+    }
+
+    #[repr(C)]
+    #[derive(Copy, Clone, Debug, PartialEq, bytemuck::Pod, bytemuck::Zeroable)]
+    struct State {
+        velocity: [f32; 3],
+        mass: f32,
+        kind: u32,
+    }
+
+    #[tokio::test]
+    async fn neighbor_search_sorts_attributes() -> anyhow::Result<()> {
+        let engine = Engine::new().await?;
+
+        let mut search = engine
+            .neighbor_search()
+            .search_radius(0.5)
+            .domain([0.0; 3], [8.0, 8.0, 8.0])
+            .max_neighbors(256)
+            .init()?;
+
+        let particles = gen_clustered_particles(0, 1000, &[[1.0, 1.0, 1.0], [5.0, 2.0, 4.0]], 3.0);
+        let ids: Vec<u32> = (0..particles.len() as u32).collect();
+        let states: Vec<State> = ids
+            .iter()
+            .map(|&id| State {
+                velocity: [id as f32, -(id as f32), 0.5],
+                mass: 1.0 + id as f32,
+                kind: id % 3,
+            })
+            .collect();
+
+        let id_attribute = search.add_attribute(&ids)?;
+        let state_attribute = search.add_attribute(&states)?;
+        search.build(&particles).await?;
+
+        let order = search.order().await?;
+        assert_slices_eq(&search.sorted_attribute(id_attribute).await?, &order);
+        let sorted_states = search.sorted_attribute(state_attribute).await?;
+        for (state, &original) in std::iter::zip(sorted_states, &order) {
+            assert_eq!(state, states[original as usize]);
+        }
+
+        // new values are picked up by the next rebuild
+        let reversed: Vec<u32> = ids.iter().rev().copied().collect();
+        search.write_attribute(id_attribute, &reversed)?;
+        search.rebuild().await?;
+        let expected: Vec<u32> = order.iter().map(|&i| reversed[i as usize]).collect();
+        assert_slices_eq(&search.sorted_attribute(id_attribute).await?, &expected);
+
+        assert!(search.add_attribute(&[0u16; 2000]).is_err());
+        search.write_attribute(id_attribute, &ids[1..])?;
+        assert!(search.rebuild().await.is_err());
+
+        // attributes of another search are rejected rather than mixed up
+        let mut other = engine.neighbor_search().domain([0.0; 3], [8.0; 3]).init()?;
+        other.add_attribute(&ids)?;
+        assert!(other.write_attribute(id_attribute, &ids).is_err());
+        assert!(other.sorted_attribute(id_attribute).await.is_err());
+        assert!(other.attribute_buffer(state_attribute).is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn neighbor_search_rejects_invalid_input() -> anyhow::Result<()> {
+        let engine = Engine::new().await?;
+
+        assert!(engine.neighbor_search().cell_width(0.5).search_radius(1.0).domain([0.0; 3], [4.0; 3]).init().is_err());
+        assert!(engine.neighbor_search().init().is_err());
+        assert!(engine.neighbor_search().cell_width(1e-3).search_radius(1e-3).domain([0.0; 3], [20.0; 3]).init().is_err());
+        assert!(engine.neighbor_search().domain([0.0; 3], [4.5, 4.0, 4.0]).periodic([true, false, false]).init().is_err());
+        assert!(engine.neighbor_search().domain([0.0; 3], [4.0, 2.0, 4.0]).periodic([false, true, false]).init().is_err());
+        assert!(engine.neighbor_search().hash_table(0).init().is_err());
+        assert!(engine.neighbor_search().hash_table(64).domain([0.0; 3], [4.0; 3]).periodic([true; 3]).init().is_err());
+
+        // the neighbors are bound whole
+        let max_neighbors = (max_storage_size(&engine) / 4) as u32;
+        assert!(engine.neighbor_search().domain([0.0; 3], [4.0; 3]).max_neighbors(max_neighbors + 1).init().is_err());
+        let mut search = engine.neighbor_search().domain([0.0; 3], [4.0; 3]).max_neighbors(max_neighbors).init()?;
+        search.build(&[Vec3A::new(1.0, 1.0, 1.0)]).await?;
+        assert!(search.build(&[Vec3A::new(1.0, 1.0, 1.0); 2]).await.is_err());
+
+        let mut search = engine.neighbor_search().domain([0.0; 3], [4.0, 4.0, 4.0]).max_neighbors(1).init()?;
+        assert!(search.query().await.is_err());
+        assert!(search.build(&[]).await.is_err());
+        assert!(search.build(&[Vec3A::new(1.0, 5.0, 1.0)]).await.is_err());
+        assert!(search.build(&[Vec3A::new(1.0, f32::NAN, 1.0)]).await.is_err());
+
+        search.build(&[Vec3A::new(1.0, 1.0, 1.0), Vec3A::new(1.5, 1.0, 1.0), Vec3A::new(1.0, 1.5, 1.0)]).await?;
+        assert!(search.query().await.is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,215 @@
+use std::sync::Arc;
+
+use crate::Engine;
+
+pub(crate) const UNIFORM: wgpu::BindingType = wgpu::BindingType::Buffer {
+    ty: wgpu::BufferBindingType::Uniform,
+    has_dynamic_offset: false,
+    min_binding_size: None,
+};
+
+pub(crate) const STORAGE: wgpu::BindingType = wgpu::BindingType::Buffer {
+    ty: wgpu::BufferBindingType::Storage { read_only: false },
+    has_dynamic_offset: false,
+    min_binding_size: None,
+};
+
+pub(crate) const STORAGE_READ: wgpu::BindingType = wgpu::BindingType::Buffer {
+    ty: wgpu::BufferBindingType::Storage { read_only: true },
+    has_dynamic_offset: false,
+    min_binding_size: None,
+};
+
+pub(crate) const STORAGE_DYNAMIC: wgpu::BindingType = wgpu::BindingType::Buffer {
+    ty: wgpu::BufferBindingType::Storage { read_only: false },
+    has_dynamic_offset: true,
+    min_binding_size: None,
+};
+
+/// A compute pipeline along with the layout of its only bind group.
+pub struct Pipeline {
+    pub bind_group_layout: wgpu::BindGroupLayout,
+    pub pipeline: wgpu::ComputePipeline,
+}
+
+impl Engine {
+    /// Returns the pipeline for `entry_point` of one of the [`Engine::kernels`], creating and
+    /// caching it on first use. Bindings are numbered in order.
+    pub(crate) fn pipeline(&self, kernel: &str, entry_point: &str, bindings: &[wgpu::BindingType]) -> Arc<Pipeline> {
+        let key = format!("{}/{}", kernel, entry_point);
+        self.cached_pipeline(key, bindings, |key| {
+            let module = self.kernels.get(kernel).unwrap_or_else(|| panic!("no kernel named {}", kernel));
+            self.create_pipeline(key, module, entry_point, bindings)
+        })
+    }
+
+    /// Like [`Engine::pipeline`], for kernels whose source is generated per `specialization`,
+    /// e.g. for each element type. The module is only compiled on first use.
+    pub(crate) fn specialized_pipeline(
+        &self,
+        kernel: &str,
+        specialization: &str,
+        source: impl FnOnce() -> String,
+        entry_point: &str,
+        bindings: &[wgpu::BindingType],
+    ) -> Arc<Pipeline> {
+        let key = format!("{}<{}>/{}", kernel, specialization, entry_point);
+        self.cached_pipeline(key, bindings, |key| {
+            let module = self.device.create_shader_module(wgpu::ShaderModuleDescriptor {
+                label: Some(key),
+                source: wgpu::ShaderSource::Wgsl(source().into()),
+            });
+            self.create_pipeline(key, &module, entry_point, bindings)
+        })
+    }
+
+    /// Like [`Engine::specialized_pipeline`], for sources built from caller input. Fails with the
+    /// error of `source` or the validation error of the module or pipeline instead of panicking,
+    /// and leaves nothing in the cache then.
+    pub(crate) fn checked_specialized_pipeline(
+        &self,
+        kernel: &str,
+        specialization: &str,
+        source: impl FnOnce() -> anyhow::Result<String>,
+        entry_point: &str,
+        bindings: &[wgpu::BindingType],
+    ) -> anyhow::Result<Arc<Pipeline>> {
+        let key = format!("{}<{}>/{}", kernel, specialization, entry_point);
+        self.try_cached_pipeline(key, bindings, |key| {
+            let source = source()?;
+            self.device.push_error_scope(wgpu::ErrorFilter::Validation);
+            let module = self.device.create_shader_module(wgpu::ShaderModuleDescriptor {
+                label: Some(key),
+                source: wgpu::ShaderSource::Wgsl(source.into()),
+            });
+            let pipeline = self.create_pipeline(key, &module, entry_point, bindings);
+            match futures::executor::block_on(self.device.pop_error_scope()) {
+                Some(error) => Err(anyhow::anyhow!("invalid {}: {}", key, error)),
+                None => Ok(pipeline),
+            }
+        })
+    }
+
+    /// Pipelines are cached per name and binding types, as the same entry point can be used
+    /// with different bind group layouts. The name doubles as the label.
+    fn cached_pipeline(
+        &self,
+        name: String,
+        bindings: &[wgpu::BindingType],
+        create: impl FnOnce(&str) -> Pipeline,
+    ) -> Arc<Pipeline> {
+        self.try_cached_pipeline(name, bindings, |name| Ok::<_, std::convert::Infallible>(create(name)))
+            .unwrap_or_else(|never| match never {})
+    }
+
+    fn try_cached_pipeline<E>(
+        &self,
+        name: String,
+        bindings: &[wgpu::BindingType],
+        create: impl FnOnce(&str) -> Result<Pipeline, E>,
+    ) -> Result<Arc<Pipeline>, E> {
+        let key = format!("{}{:?}", name, bindings);
+        let mut pipelines = self.pipelines.lock().unwrap();
+
+        if let Some(pipeline) = pipelines.get(&key) {
+            return Ok(pipeline.clone());
+        }
+
+        let pipeline = Arc::new(create(&name)?);
+        pipelines.insert(key, pipeline.clone());
+
+        Ok(pipeline)
+    }
+
+    fn create_pipeline(
+        &self,
+        label: &str,
+        module: &wgpu::ShaderModule,
+        entry_point: &str,
+        bindings: &[wgpu::BindingType],
+    ) -> Pipeline {
+        let entries: Vec<wgpu::BindGroupLayoutEntry> = bindings
+            .iter()
+            .enumerate()
+            .map(|(binding, &ty)| wgpu::BindGroupLayoutEntry {
+                binding: binding as u32,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty,
+                count: None,
+            })
+            .collect();
+
+        let bind_group_layout =
+            self.device
+                .create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                    label: Some(label),
+                    entries: &entries,
+                });
+
+        let pipeline_layout = self
+            .device
+            .create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some(label),
+                bind_group_layouts: &[&bind_group_layout],
+                push_constant_ranges: &[],
+            });
+
+        let pipeline = self
+            .device
+            .create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some(label),
+                layout: Some(&pipeline_layout),
+                module,
+                entry_point,
+                compilation_options: Default::default(),
+            });
+
+        Pipeline {
+            bind_group_layout,
+            pipeline,
+        }
+    }
+
+    /// Binds each buffer in full to the binding of the same index.
+    pub(crate) fn bind_buffers(&self, pipeline: &Pipeline, bufs: &[&wgpu::Buffer]) -> wgpu::BindGroup {
+        let entries: Vec<wgpu::BindGroupEntry> = bufs
+            .iter()
+            .enumerate()
+            .map(|(binding, buf)| wgpu::BindGroupEntry {
+                binding: binding as u32,
+                resource: buf.as_entire_binding(),
+            })
+            .collect();
+
+        self.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.bind_group_layout,
+            entries: &entries,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ScanKind;
+
+    #[tokio::test]
+    async fn pipelines_are_cached() -> anyhow::Result<()> {
+        let engine = Engine::new().await?;
+
+        let first = engine.pipeline("fenns_gather", "main", &[STORAGE_READ, STORAGE_READ, STORAGE]);
+        let second = engine.pipeline("fenns_gather", "main", &[STORAGE_READ, STORAGE_READ, STORAGE]);
+        assert!(Arc::ptr_eq(&first, &second));
+        let dynamic = engine.pipeline("fenns_gather", "main", &[STORAGE_READ, STORAGE_READ, STORAGE_DYNAMIC]);
+        assert!(!Arc::ptr_eq(&first, &dynamic));
+
+        engine.prefix_sum(&[1u32; 1000], ScanKind::Inclusive).await?;
+
+        let keys = engine.pipelines.lock().unwrap().len();
+        engine.prefix_sum(&[1u32; 1000], ScanKind::Inclusive).await?;
+        assert_eq!(engine.pipelines.lock().unwrap().len(), keys);
+
+        Ok(())
+    }
+}
@@ -1,11 +1,311 @@
+use std::ops::Range;
+use std::sync::Arc;
+
+use anyhow::Context;
 use wgpu::util::DeviceExt;
 
+use crate::pipeline::{Pipeline, STORAGE, STORAGE_DYNAMIC, UNIFORM};
 use crate::Engine;
 
+const SCAN_TILE: &str = include_str!("kernels/scan_tile.wgsl");
+const SCAN_WORKGROUP_SHARED: &str = include_str!("kernels/scan_workgroup_shared.wgsl");
+const SCAN_WORKGROUP_SUBGROUP: &str = include_str!("kernels/scan_workgroup_subgroup.wgsl");
+const PSUM1: &str = include_str!("kernels/psum1.wgsl");
+const PSUM2: &str = include_str!("kernels/psum2.wgsl");
+const PSUM_SINGLE_PASS: &str = include_str!("kernels/psum_single_pass.wgsl");
+const SCAN_AXIS: &str = include_str!("kernels/scan_axis.wgsl");
+/// Elements of a line scanned one after another by a thread of the scan_axis kernels.
+const AXIS_CHUNK_LEN: u32 = 64;
+
+const RANGE_DECLS: &str = "
+    struct ScanRange { head: u32 }
+    @group(0) @binding(2)
+    var<uniform> scan_range: ScanRange;
+    fn in_range(i: u32) -> bool { return scan_range.head + i < arrayLength(&buf); }
+";
+
+/// Elements scanned by one workgroup, 8 for each of its 256 threads, see scan_workgroup.wgsl.
+pub(crate) const TILE_LEN: u64 = 256 * 8;
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+/// Associative operator applied by the scan.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ScanOp {
+    Add,
+    Min,
+    Max,
+    /// Bitwise, not supported for floats.
+    And,
+    /// Bitwise, not supported for floats.
+    Or,
+    /// Bitwise, not supported for floats.
+    Xor,
+}
+
+/// Element types the scan kernels can be specialized for.
+pub trait ScanElement: bytemuck::Pod + sealed::Sealed {
+    /// Declares `Value`, `identity_value()` and `combine_values()`, or returns `None` if the type
+    /// doesn't support `op`.
+    #[doc(hidden)]
+    fn wgsl(op: ScanOp) -> Option<String>;
+}
+
+fn wgsl_decls(ty: &str, helpers: &str, identity: &str, combine: &str) -> String {
+    format!(
+        "alias Value = {};\n{}\nfn identity_value() -> Value {{ return {}; }}\nfn combine_values(a: Value, b: Value) -> Value {{ return {}; }}\n",
+        ty, helpers, identity, combine,
+    )
+}
+
+/// The psum kernels scan `Elem`s, which are plain values outside of segmented scans.
+pub(crate) const PLAIN_ELEM: &str = "
+    alias Elem = Value;
+    fn identity() -> Elem { return identity_value(); }
+    fn combine(a: Elem, b: Elem) -> Elem { return combine_values(a, b); }
+";
+
+impl sealed::Sealed for u32 {}
+impl ScanElement for u32 {
+    fn wgsl(op: ScanOp) -> Option<String> {
+        let (identity, combine) = match op {
+            ScanOp::Add => ("0u", "a + b"),
+            ScanOp::Min => ("0xffffffffu", "min(a, b)"),
+            ScanOp::Max => ("0u", "max(a, b)"),
+            ScanOp::And => ("0xffffffffu", "a & b"),
+            ScanOp::Or => ("0u", "a | b"),
+            ScanOp::Xor => ("0u", "a ^ b"),
+        };
+        Some(wgsl_decls("u32", "", identity, combine))
+    }
+}
+
+impl sealed::Sealed for i32 {}
+impl ScanElement for i32 {
+    fn wgsl(op: ScanOp) -> Option<String> {
+        let (identity, combine) = match op {
+            ScanOp::Add => ("0i", "a + b"),
+            ScanOp::Min => ("2147483647i", "min(a, b)"),
+            ScanOp::Max => ("-2147483647i - 1i", "max(a, b)"),
+            ScanOp::And => ("-1i", "a & b"),
+            ScanOp::Or => ("0i", "a | b"),
+            ScanOp::Xor => ("0i", "a ^ b"),
+        };
+        Some(wgsl_decls("i32", "", identity, combine))
+    }
+}
+
+impl sealed::Sealed for f32 {}
+impl ScanElement for f32 {
+    fn wgsl(op: ScanOp) -> Option<String> {
+        let (identity, combine) = match op {
+            ScanOp::Add => ("0.0", "a + b"),
+            ScanOp::Min => ("bitcast<f32>(0x7f800000u)", "min(a, b)"),
+            ScanOp::Max => ("bitcast<f32>(0xff800000u)", "max(a, b)"),
+            ScanOp::And | ScanOp::Or | ScanOp::Xor => return None,
+        };
+        Some(wgsl_decls("f32", "", identity, combine))
+    }
+}
+
+/// Stored as (low, high) u32 pairs, as WGSL has no 64-bit integers.
+impl sealed::Sealed for u64 {}
+impl ScanElement for u64 {
+    fn wgsl(op: ScanOp) -> Option<String> {
+        const HELPERS: &str = "
+            fn add64(a: vec2u, b: vec2u) -> vec2u {
+                let low = a.x + b.x;
+                return vec2u(low, a.y + b.y + select(0u, 1u, low < a.x));
+            }
+
+            fn less64(a: vec2u, b: vec2u) -> bool {
+                return a.y < b.y || (a.y == b.y && a.x < b.x);
+            }
+        ";
+
+        let (identity, combine) = match op {
+            ScanOp::Add => ("vec2u(0u)", "add64(a, b)"),
+            ScanOp::Min => ("vec2u(0xffffffffu)", "select(b, a, less64(a, b))"),
+            ScanOp::Max => ("vec2u(0u)", "select(a, b, less64(a, b))"),
+            ScanOp::And => ("vec2u(0xffffffffu)", "a & b"),
+            ScanOp::Or => ("vec2u(0u)", "a | b"),
+            ScanOp::Xor => ("vec2u(0u)", "a ^ b"),
+        };
+        Some(wgsl_decls("vec2u", HELPERS, identity, combine))
+    }
+}
+
+pub(crate) fn scan_decls<T: ScanElement>(op: ScanOp) -> anyhow::Result<String> {
+    T::wgsl(op).with_context(|| format!("{:?} scans are not supported for {}", op, std::any::type_name::<T>()))
+}
+
+/// Declares `shuffle_up()` and `subgroup_scan()` for scan_workgroup_subgroup.wgsl. Without an
+/// `inclusive_builtin` for the operator, the subgroup scan shuffles `Elem`s up instead.
+pub(crate) fn subgroup_decls(inclusive_builtin: Option<&str>, shuffle_up: &str) -> String {
+    let subgroup_scan = match inclusive_builtin {
+        Some(builtin) => format!("return {}(value);", builtin),
+        None => "
+            var sum = value;
+            for (var delta = 1u; delta < size; delta <<= 1u) {
+                let other = shuffle_up(sum, delta);
+                if lane >= delta {
+                    sum = combine(other, sum);
+                }
+            }
+            return sum;
+        "
+        .to_string(),
+    };
+    format!(
+        "fn shuffle_up(e: Elem, delta: u32) -> Elem {{ return {}; }}\nfn subgroup_scan(value: Elem, lane: u32, size: u32) -> Elem {{ {} }}\n",
+        shuffle_up, subgroup_scan,
+    )
+}
+
+/// Appends scan_tile.wgsl, the workgroup scan and `kernel` to the `Elem` declarations.
+pub(crate) fn scan_source(decls: &str, subgroups: bool, kernel: &str) -> String {
+    let scan_workgroup = if subgroups { SCAN_WORKGROUP_SUBGROUP } else { SCAN_WORKGROUP_SHARED };
+    format!("{}\n{}\n{}\n{}", decls, SCAN_TILE, scan_workgroup, kernel)
+}
+
+/// Declares where the scanned elements are in the `buf` binding of the scan kernels: `in_range()`
+/// past the first `scan_range.head` elements, as passed by [`Engine::range_params`], and `buf_idx()` in
+/// order from there or backwards from the end.
+fn range_decls(decls: &str, direction: ScanDirection) -> String {
+    let buf_idx = match direction {
+        ScanDirection::Forward => "scan_range.head + i",
+        ScanDirection::Reverse => "arrayLength(&buf) - 1u - i",
+    };
+    format!(
+        "{}{}fn buf_idx(i: u32) -> u32 {{ return {}; }}\n",
+        decls, RANGE_DECLS, buf_idx,
+    )
+}
+
+/// The `Elem` declarations of the scan kernels, what tells them apart among the specialized
+/// pipelines, and the size of an `Elem`.
+#[derive(Copy, Clone)]
+pub(crate) struct ElemDecls<'a> {
+    pub(crate) specialization: &'a str,
+    pub(crate) decls: &'a str,
+    pub(crate) size: u64,
+}
+
+/// Declares how psum_single_pass.wgsl splits `Elem`s into words.
+fn single_pass_decls(decls: &str, elem_size: u64) -> String {
+    let words = elem_size / 4;
+    let (to_words, from_words) = match words {
+        1 => ("array<u32, 1>(bitcast<u32>(e))", "bitcast<Elem>(w[0])"),
+        2 => ("array<u32, 2>(bitcast<vec2u>(e).x, bitcast<vec2u>(e).y)", "bitcast<Elem>(vec2u(w[0], w[1]))"),
+        _ => unreachable!("scan elements are one or two words"),
+    };
+    format!(
+        "{}\nconst ELEM_WORDS: u32 = {}u;\nfn elem_to_words(e: Elem) -> array<u32, {}> {{ return {}; }}\nfn elem_from_words(w: array<u32, {}>) -> Elem {{ return {}; }}\n",
+        decls, words, words, to_words, words, from_words,
+    )
+}
+
+/// A range of elements of a device buffer, like a [`wgpu::BufferSlice`] that counts elements
+/// rather than bytes.
+#[derive(Copy, Clone, Debug)]
+pub struct BufferRange<'a> {
+    pub buffer: &'a wgpu::Buffer,
+    /// The index of the first element.
+    pub offset: u64,
+    /// The number of elements.
+    pub len: u64,
+}
+
+impl<'a> BufferRange<'a> {
+    /// The elements `range` of `buffer`.
+    pub fn new(buffer: &'a wgpu::Buffer, range: Range<u64>) -> Self {
+        assert!(range.start <= range.end, "range starts at {} but ends at {}", range.start, range.end);
+        Self {
+            buffer,
+            offset: range.start,
+            len: range.end - range.start,
+        }
+    }
+
+    /// All the elements of `elem_size` bytes of `buffer`.
+    pub(crate) fn whole(buffer: &'a wgpu::Buffer, elem_size: u64) -> Self {
+        Self {
+            buffer,
+            offset: 0,
+            len: buffer.size() / elem_size,
+        }
+    }
+
+    /// Splits the start of the range into a binding offset in bytes with the given alignment, and
+    /// the index of the first element from there.
+    fn aligned_start(&self, elem_size: u64, alignment: u64) -> (u64, u64) {
+        let start = self.offset * elem_size;
+        let base = start / alignment * alignment;
+        (base, (start - base) / elem_size)
+    }
+}
+
+/// Whether each output element includes the input element at the same position, with `+`
+/// standing for the [`ScanOp`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ScanKind {
+    /// `out[i] = in[0] + ... + in[i]`
+    Inclusive,
+    /// `out[i] = in[0] + ... + in[i - 1]`, starting at the identity of the operator.
+    Exclusive,
+}
+
+/// Which way a scan runs through its input, with `+` standing for the [`ScanOp`].
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum ScanDirection {
+    /// From the first element to the last, a prefix scan.
+    #[default]
+    Forward,
+    /// From the last element to the first, a suffix scan: `out[i] = in[i] + ... + in[n - 1]`
+    /// when inclusive, and the identity for the last element when exclusive.
+    Reverse,
+}
+
+/// How [`Engine::scan_inner`] and the functions built on it scan a buffer.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum ScanStrategy {
+    /// Scans tiles of 2048 elements, then recursively their totals, and adds those back with a second
+    /// kernel. Runs anywhere.
+    #[default]
+    MultiLevel,
+    /// A chained scan with decoupled look-back, which reads and writes every element once in a
+    /// single dispatch. Relies on the device eventually running every workgroup it has started.
+    /// Ranges too large to bind at once are scanned with [`ScanStrategy::MultiLevel`].
+    SinglePass,
+}
+
 impl Engine {
-    pub async fn prefix_sum(&self, input: &[u32]) -> anyhow::Result<Vec<u32>> {
-        if input.len() <= 1 {
-            return Ok(Vec::from(input));
+    pub async fn prefix_sum<T: ScanElement>(&self, input: &[T], kind: ScanKind) -> anyhow::Result<Vec<T>> {
+        self.scan(input, ScanOp::Add, kind, ScanDirection::Forward).await
+    }
+
+    /// Sums a buffer of `T`s in place.
+    pub fn prefix_sum_inner<T: ScanElement>(&self, buf: &wgpu::Buffer, kind: ScanKind) {
+        self.scan_inner::<T>(buf, ScanOp::Add, kind, ScanDirection::Forward)
+            .expect("every scan element supports addition");
+    }
+
+    /// Like [`Engine::prefix_sum`], summing from the last element to the first.
+    pub async fn suffix_sum<T: ScanElement>(&self, input: &[T], kind: ScanKind) -> anyhow::Result<Vec<T>> {
+        self.scan(input, ScanOp::Add, kind, ScanDirection::Reverse).await
+    }
+
+    pub async fn scan<T: ScanElement>(
+        &self,
+        input: &[T],
+        op: ScanOp,
+        kind: ScanKind,
+        direction: ScanDirection,
+    ) -> anyhow::Result<Vec<T>> {
+        if input.is_empty() {
+            return Ok(vec![]);
         }
 
         let storage_buffer = self
@@ -18,148 +318,512 @@ impl Engine {
                     | wgpu::BufferUsages::COPY_SRC,
             });
 
-        self.prefix_sum_inner(&storage_buffer);
+        self.scan_inner::<T>(&storage_buffer, op, kind, direction)?;
 
         self.map_buffer(&storage_buffer).await
     }
 
-    pub fn prefix_sum_inner(&self, buf: &wgpu::Buffer) {
-        let input_len = buf.size() / 4;
+    /// Scans a buffer of `T`s in place, failing if `T` doesn't support `op`.
+    pub fn scan_inner<T: ScanElement>(
+        &self,
+        buf: &wgpu::Buffer,
+        op: ScanOp,
+        kind: ScanKind,
+        direction: ScanDirection,
+    ) -> anyhow::Result<()> {
+        let elem_size = std::mem::size_of::<T>() as u64;
+        self.scan_range_inner::<T>(BufferRange::whole(buf, elem_size), op, kind, direction)
+    }
+
+    /// Sums a range of a buffer of `T`s in place, leaving the rest of the buffer untouched.
+    pub fn prefix_sum_range_inner<T: ScanElement>(&self, range: BufferRange<'_>, kind: ScanKind) -> anyhow::Result<()> {
+        self.scan_range_inner::<T>(range, ScanOp::Add, kind, ScanDirection::Forward)
+    }
+
+    /// Scans a range of a buffer of `T`s in place, leaving the rest of the buffer untouched. The
+    /// range may start and end anywhere.
+    pub fn scan_range_inner<T: ScanElement>(
+        &self,
+        range: BufferRange<'_>,
+        op: ScanOp,
+        kind: ScanKind,
+        direction: ScanDirection,
+    ) -> anyhow::Result<()> {
+        let mut encoder = self.device.create_command_encoder(&Default::default());
+        self.record_scan_range::<T>(&mut encoder, range, op, kind, direction)?;
+        self.queue.submit(Some(encoder.finish()));
+
+        Ok(())
+    }
+
+    /// Records [`Engine::scan_range_inner`] into `encoder` without submitting it, e.g. to run
+    /// several scans in a single submission.
+    pub fn record_scan_range<T: ScanElement>(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        range: BufferRange<'_>,
+        op: ScanOp,
+        kind: ScanKind,
+        direction: ScanDirection,
+    ) -> anyhow::Result<()> {
+        let elem_size = std::mem::size_of::<T>() as u64;
+        anyhow::ensure!(
+            (range.offset + range.len) * elem_size <= range.buffer.size(),
+            "{} elements at {} are out of bounds of a buffer of {} elements",
+            range.len,
+            range.offset,
+            range.buffer.size() / elem_size,
+        );
+
+        let decls = self.plain_elem_decls::<T>(op)?;
+        let specialization = format!("{}, {:?}", std::any::type_name::<T>(), op);
+
+        if range.len == 0 {
+            return Ok(());
+        }
+
+        let elem = ElemDecls {
+            specialization: &specialization,
+            decls: &decls,
+            size: elem_size,
+        };
+        // the single pass binds the whole range at once
+        let binding_size = range.len * elem_size + self.storage_alignment();
+        let single_pass = self.scan_strategy == ScanStrategy::SinglePass
+            && binding_size <= self.device.limits().max_storage_buffer_binding_size as u64;
+        if single_pass {
+            self.scan_single_pass(encoder, range, elem, kind, direction);
+        } else {
+            self.scan_levels(encoder, range, elem, kind, direction);
+        }
+        Ok(())
+    }
+
+    /// Sums a 2D or 3D grid of `T`s into its summed-area table, where every element is the sum
+    /// of the box from the first element up to it. See [`Engine::summed_area_table_inner`].
+    pub async fn summed_area_table<T: ScanElement>(&self, input: &[T], dims: &[u32]) -> anyhow::Result<Vec<T>> {
+        if input.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let storage_buffer = self
+            .device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("storage buffer"),
+                contents: bytemuck::cast_slice(input),
+                usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+            });
+
+        let elem_size = std::mem::size_of::<T>() as u64;
+        self.summed_area_table_inner::<T>(BufferRange::whole(&storage_buffer, elem_size), dims)?;
+
+        self.map_buffer(&storage_buffer).await
+    }
+
+    /// Sums a row-major grid of `T`s in place along each of its axes in turn, making it a
+    /// summed-area table. `dims` holds the sizes along x, y and possibly z, with x varying
+    /// fastest as in the `z * dim_y * dim_x + y * dim_x + x` cell indices of the FENNS kernels.
+    /// The grid fills `range`, and the rest of the buffer is left untouched.
+    pub fn summed_area_table_inner<T: ScanElement>(&self, range: BufferRange<'_>, dims: &[u32]) -> anyhow::Result<()> {
+        let elem_size = std::mem::size_of::<T>() as u64;
+        anyhow::ensure!(
+            (range.offset + range.len) * elem_size <= range.buffer.size(),
+            "{} elements at {} are out of bounds of a buffer of {} elements",
+            range.len,
+            range.offset,
+            range.buffer.size() / elem_size,
+        );
+        anyhow::ensure!(
+            (1..=3).contains(&dims.len()) && dims.iter().map(|&dim| dim as u64).product::<u64>() == range.len,
+            "a grid of {:?} doesn't match a range of {} elements",
+            dims,
+            range.len,
+        );
+        if range.len == 0 {
+            return Ok(());
+        }
+
+        let decls = scan_decls::<T>(ScanOp::Add)?;
+        let mut encoder = self.device.create_command_encoder(&Default::default());
+        let mut stride = 1;
+        for &dim in dims {
+            self.scan_axis_lines::<T>(&mut encoder, range, dim, stride, &decls);
+            stride *= dim;
+        }
+        self.queue.submit(Some(encoder.finish()));
+
+        Ok(())
+    }
+
+    /// Scans the lines of `len` elements `stride` apart that make up `range`. Lines longer than
+    /// [`AXIS_CHUNK_LEN`] are split into chunks scanned by threads of their own, and the chunk
+    /// totals are scanned as lines in turn before being added back.
+    fn scan_axis_lines<T: ScanElement>(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        range: BufferRange<'_>,
+        len: u32,
+        stride: u32,
+        decls: &str,
+    ) {
+        let elem_size = std::mem::size_of::<T>() as u64;
+        let lines = range.len / len as u64;
+        let chunks = len.div_ceil(AXIS_CHUNK_LEN);
+        let pipeline = |entry_point, bindings: &[wgpu::BindingType]| {
+            self.specialized_pipeline(
+                "scan_axis",
+                std::any::type_name::<T>(),
+                || format!("{}\n{}", decls, SCAN_AXIS),
+                entry_point,
+                bindings,
+            )
+        };
+
+        // the binding starts at an aligned offset before the range, and ends with it
+        let (base, head) = range.aligned_start(elem_size, self.storage_alignment());
+        let params = self
+            .device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("scan axis params"),
+                contents: bytemuck::cast_slice(&[len, stride, len.min(AXIS_CHUNK_LEN), chunks, head as u32, lines as u32, 0, 0]),
+                usage: wgpu::BufferUsages::UNIFORM,
+            });
+        let bind_group = |pipeline: &Pipeline, totals: Option<&wgpu::Buffer>| {
+            let mut entries = vec![
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: params.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                        buffer: range.buffer,
+                        offset: base,
+                        size: ((head + range.len) * elem_size).try_into().ok(),
+                    }),
+                },
+            ];
+            entries.extend(totals.map(|totals| wgpu::BindGroupEntry {
+                binding: 2,
+                resource: totals.as_entire_binding(),
+            }));
+            self.device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: None,
+                layout: &pipeline.bind_group_layout,
+                entries: &entries,
+            })
+        };
+
+        if chunks == 1 {
+            let pipeline = pipeline("main", &[UNIFORM, STORAGE]);
+            self.dispatch_scan_axis(encoder, &pipeline, &bind_group(&pipeline, None), lines);
+            return;
+        }
+
+        let totals = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("scan axis totals"),
+            size: elem_size * lines * chunks as u64,
+            usage: wgpu::BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+
+        let pipeline_chunks = pipeline("main_chunks", &[UNIFORM, STORAGE, STORAGE]);
+        let bind_group_chunks = bind_group(&pipeline_chunks, Some(&totals));
+        self.dispatch_scan_axis(encoder, &pipeline_chunks, &bind_group_chunks, lines * chunks as u64);
+
+        // the totals of a line are contiguous
+        self.scan_axis_lines::<T>(encoder, BufferRange::whole(&totals, elem_size), chunks, 1, decls);
+
+        let pipeline_carries = pipeline("main_carries", &[UNIFORM, STORAGE, STORAGE]);
+        let bind_group_carries = bind_group(&pipeline_carries, Some(&totals));
+        self.dispatch_scan_axis(encoder, &pipeline_carries, &bind_group_carries, lines * chunks as u64);
+    }
+
+    fn dispatch_scan_axis(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        pipeline: &Pipeline,
+        bind_group: &wgpu::BindGroup,
+        threads: u64,
+    ) {
+        const WG_SIZE: u64 = 256;
+        const MAX_WORKGROUPS: u64 = 65535;
+
+        let workgroups = threads.div_ceil(WG_SIZE);
+        let mut cpass = encoder.begin_compute_pass(&Default::default());
+        cpass.insert_debug_marker("scan_axis dispatch");
+        cpass.set_pipeline(&pipeline.pipeline);
+        cpass.set_bind_group(0, bind_group, &[]);
+        cpass.dispatch_workgroups(
+            workgroups.min(MAX_WORKGROUPS) as u32,
+            workgroups.div_ceil(MAX_WORKGROUPS) as u32,
+            1,
+        );
+    }
+
+    /// Declares plain `T`s as the `Elem`s of the scan kernels.
+    pub(crate) fn plain_elem_decls<T: ScanElement>(&self, op: ScanOp) -> anyhow::Result<String> {
+        let mut decls = scan_decls::<T>(op)? + PLAIN_ELEM;
+        if self.capabilities.subgroups {
+            // the 32 bit sums have a builtin
+            let builtin = (op == ScanOp::Add && std::mem::size_of::<T>() == 4).then_some("subgroupInclusiveAdd");
+            decls += &subgroup_decls(builtin, "subgroupShuffleUp(e, delta)");
+        }
+        Ok(decls)
+    }
+
+    /// Records the scan of a range of `Elem`s in place.
+    pub(crate) fn scan_levels(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        range: BufferRange<'_>,
+        elem: ElemDecls<'_>,
+        kind: ScanKind,
+        direction: ScanDirection,
+    ) {
+        let input_len = range.len;
+        let elem_size = elem.size;
 
         let next_buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
             label: Some("next buffer"),
-            size: 4 * (input_len).div_ceil(256),
+            size: elem_size * input_len.div_ceil(TILE_LEN),
             usage: wgpu::BufferUsages::STORAGE
                 | wgpu::BufferUsages::COPY_DST
                 | wgpu::BufferUsages::COPY_SRC,
             mapped_at_creation: false,
         });
 
-        let bufs = [buf, &next_buffer];
-
-        self.dispatch_psum_kernel(&bufs, "psum1", 0);
+        let entry_point = match kind {
+            ScanKind::Inclusive => "main",
+            ScanKind::Exclusive => "main_exclusive",
+        };
+        let dispatch = match direction {
+            ScanDirection::Forward => Self::dispatch_psum_kernel,
+            ScanDirection::Reverse => Self::dispatch_reverse_psum_kernel,
+        };
+        let pipeline = self.psum_pipeline("psum1", elem, entry_point, direction);
+        dispatch(self, encoder, range, &next_buffer, &pipeline, "psum1", elem_size);
 
-        if input_len > 256 {
-            self.prefix_sum_inner(&next_buffer);
-            self.dispatch_psum_kernel(&bufs, "psum2", 1);
+        // psum2 adds the inclusive sums of the preceding workgroups either way, the tiles'
+        // totals are in the order of the scan
+        if input_len > TILE_LEN {
+            let next_range = BufferRange::whole(&next_buffer, elem_size);
+            self.scan_levels(encoder, next_range, elem, ScanKind::Inclusive, ScanDirection::Forward);
+            let pipeline = self.psum_pipeline("psum2", elem, "main", direction);
+            dispatch(self, encoder, range, &next_buffer, &pipeline, "psum2", elem_size);
         }
     }
 
-    fn dispatch_psum_kernel(&self, bufs: &[&wgpu::Buffer], kernel: &str, starting_offset: u32) {
-        const MAX_WORKGROUPS: u32 = 65535;
-        let total_wg_count = (bufs[0].size() / 4).div_ceil(256) as u32 - starting_offset;
+    /// Scans a range of plain `Elem`s in place, see [`ScanStrategy::SinglePass`].
+    fn scan_single_pass(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        range: BufferRange<'_>,
+        elem: ElemDecls<'_>,
+        kind: ScanKind,
+        direction: ScanDirection,
+    ) {
+        const MAX_WORKGROUPS: u64 = 65535;
+        let elem_size = elem.size;
+        let tiles = range.len.div_ceil(TILE_LEN);
+        let (base, head) = range.aligned_start(elem_size, self.storage_alignment());
 
-        let bind_group_layout =
-            self.device
-                .create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                    label: None,
-                    entries: &[
-                        wgpu::BindGroupLayoutEntry {
-                            binding: 0,
-                            visibility: wgpu::ShaderStages::COMPUTE,
-                            ty: wgpu::BindingType::Buffer {
-                                ty: wgpu::BufferBindingType::Storage { read_only: false },
-                                has_dynamic_offset: true,
-                                min_binding_size: None,
-                            },
-                            count: None,
-                        },
-                        wgpu::BindGroupLayoutEntry {
-                            binding: 1,
-                            visibility: wgpu::ShaderStages::COMPUTE,
-                            ty: wgpu::BindingType::Buffer {
-                                ty: wgpu::BufferBindingType::Storage { read_only: false },
-                                has_dynamic_offset: true,
-                                min_binding_size: None,
-                            },
-                            count: None,
-                        },
-                    ],
-                });
+        // the tiles publish their words in halves, see psum_single_pass.wgsl
+        let words = elem_size / 4;
 
-        let pipeline_layout = self
-            .device
-            .create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-                label: None,
-                bind_group_layouts: &[&bind_group_layout],
-                push_constant_ranges: &[],
-            });
+        let state = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("single pass scan state"),
+            size: 4 * (1 + tiles * 4 * words),
+            usage: wgpu::BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
 
-        let pipeline = self
-            .device
-            .create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
-                label: None,
-                layout: Some(&pipeline_layout),
-                module: self.kernels.get(kernel).unwrap(),
-                entry_point: "main",
-            });
+        let entry_point = match kind {
+            ScanKind::Inclusive => "main",
+            ScanKind::Exclusive => "main_exclusive",
+        };
+        let pipeline = self.specialized_pipeline(
+            "psum_single_pass",
+            &format!("{}, {:?}", elem.specialization, direction),
+            || {
+                let decls = single_pass_decls(&range_decls(elem.decls, direction), elem_size);
+                scan_source(&decls, self.capabilities.subgroups, PSUM_SINGLE_PASS)
+            },
+            entry_point,
+            &[STORAGE, STORAGE, UNIFORM],
+        );
+        let range_params = self.range_params(head);
+        let bind_group = self.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                        buffer: range.buffer,
+                        offset: base,
+                        size: ((head + range.len) * elem_size).try_into().ok(),
+                    }),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: state.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: range_params.as_entire_binding(),
+                },
+            ],
+        });
+
+        let mut cpass = encoder.begin_compute_pass(&Default::default());
+        cpass.insert_debug_marker("psum_single_pass dispatch");
+        cpass.set_pipeline(&pipeline.pipeline);
+        cpass.set_bind_group(0, &bind_group, &[]);
+        // tiles are claimed dynamically, surplus workgroups of the last row find none left
+        cpass.dispatch_workgroups(
+            tiles.min(MAX_WORKGROUPS) as u32,
+            tiles.div_ceil(MAX_WORKGROUPS) as u32,
+            1,
+        );
+    }
+
+    fn psum_pipeline(
+        &self,
+        kernel: &str,
+        elem: ElemDecls<'_>,
+        entry_point: &str,
+        direction: ScanDirection,
+    ) -> Arc<Pipeline> {
+        let source = if kernel == "psum1" { PSUM1 } else { PSUM2 };
+        self.specialized_pipeline(
+            kernel,
+            &format!("{}, {:?}", elem.specialization, direction),
+            || scan_source(&range_decls(elem.decls, direction), self.capabilities.subgroups, source),
+            entry_point,
+            &[STORAGE_DYNAMIC, STORAGE_DYNAMIC, UNIFORM],
+        )
+    }
 
-        let wg_remainder = total_wg_count % MAX_WORKGROUPS;
-        let mut buf1_size_remainder = bufs[0].size() % (MAX_WORKGROUPS as u64 * 256 * 4);
-        let buf2_size_remainder = buf1_size_remainder.div_ceil(256).max(4);
-        buf1_size_remainder -= starting_offset as u64 * 256 * 4;
+    /// Where the range starts in the bindings of the scan kernels, see range_decls().
+    fn range_params(&self, head: u64) -> wgpu::Buffer {
+        self.device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("scan range params"),
+                contents: bytemuck::cast_slice(&[head as u32, 0, 0, 0]),
+                usage: wgpu::BufferUsages::UNIFORM,
+            })
+    }
+
+    /// The alignment of the storage buffer bindings.
+    fn storage_alignment(&self) -> u64 {
+        self.device.limits().min_storage_buffer_offset_alignment as u64
+    }
+
+    /// The most workgroups of a psum1 or psum2 dispatch, so that its binding stays within
+    /// `max_storage_buffer_binding_size`. A multiple of 256, so that the dynamic offsets into the
+    /// next level stay aligned.
+    fn psum_max_workgroups(&self, tile_size: u64) -> u32 {
+        const MAX_WORKGROUPS: u64 = 65280;
+        // the bindings start up to two alignments before the first tile, see
+        // Engine::dispatch_reverse_psum_kernel
+        let max_tiles_size = self.device.limits().max_storage_buffer_binding_size as u64 - 2 * self.storage_alignment();
+        ((max_tiles_size / tile_size).min(MAX_WORKGROUPS) / 256 * 256) as u32
+    }
+
+    fn dispatch_psum_kernel(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        range: BufferRange<'_>,
+        next_buffer: &wgpu::Buffer,
+        pipeline: &Pipeline,
+        kernel: &str,
+        elem_size: u64,
+    ) {
+        // psum2 has nothing to add to the first tile
+        let starting_offset = (kernel == "psum2") as u32;
+        let tile_size = TILE_LEN * elem_size;
+        let max_workgroups = self.psum_max_workgroups(tile_size);
+        let total_wg_count = range.len.div_ceil(TILE_LEN) as u32 - starting_offset;
+        let dispatch_count = total_wg_count.div_ceil(max_workgroups);
+
+        let wg_remainder = total_wg_count - (dispatch_count - 1) * max_workgroups;
+        // the bindings start at an aligned offset before the range, and reach up to its end
+        let (base, head) = range.aligned_start(elem_size, self.storage_alignment());
+        let head_size = head * elem_size;
+        let skipped_size = starting_offset as u64 * tile_size;
+        let buf1_size_remainder = head_size + range.len * elem_size
+            - skipped_size
+            - (dispatch_count - 1) as u64 * max_workgroups as u64 * tile_size;
+        let buf2_size_remainder = wg_remainder as u64 * elem_size;
+        let range_params = self.range_params(head);
 
         let bind_group = self.device.create_bind_group(&wgpu::BindGroupDescriptor {
             label: None,
-            layout: &bind_group_layout,
+            layout: &pipeline.bind_group_layout,
             entries: &[
                 wgpu::BindGroupEntry {
                     binding: 0,
                     resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
-                        buffer: bufs[0],
-                        offset: starting_offset as u64 * 256 * 4,
+                        buffer: range.buffer,
+                        offset: base + skipped_size,
                         size: buf1_size_remainder.try_into().ok(),
                     }),
                 },
                 wgpu::BindGroupEntry {
                     binding: 1,
                     resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
-                        buffer: bufs[1],
+                        buffer: next_buffer,
                         offset: 0,
                         size: buf2_size_remainder.try_into().ok(),
                     }),
                 },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: range_params.as_entire_binding(),
+                },
             ],
         });
 
         let mut bind_group_max_dispatch = None;
-        let dispatch_count = total_wg_count.div_ceil(MAX_WORKGROUPS);
 
         if dispatch_count > 1 {
             bind_group_max_dispatch =
                 Some(self.device.create_bind_group(&wgpu::BindGroupDescriptor {
                     label: None,
-                    layout: &bind_group_layout,
+                    layout: &pipeline.bind_group_layout,
                     entries: &[
                         wgpu::BindGroupEntry {
                             binding: 0,
                             resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
-                                buffer: bufs[0],
-                                offset: starting_offset as u64 * 256 * 4,
-                                size: (MAX_WORKGROUPS as u64 * 256 * 4).try_into().ok(),
+                                buffer: range.buffer,
+                                offset: base + skipped_size,
+                                size: (head_size + max_workgroups as u64 * tile_size).try_into().ok(),
                             }),
                         },
                         wgpu::BindGroupEntry {
                             binding: 1,
                             resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
-                                buffer: bufs[1],
+                                buffer: next_buffer,
                                 offset: 0,
-                                size: (MAX_WORKGROUPS as u64 * 4).try_into().ok(),
+                                size: (max_workgroups as u64 * elem_size).try_into().ok(),
                             }),
                         },
+                        wgpu::BindGroupEntry {
+                            binding: 2,
+                            resource: range_params.as_entire_binding(),
+                        },
                     ],
                 }));
         }
 
-        let mut encoder = self.device.create_command_encoder(&Default::default());
-
         for dispatch_i in 0..dispatch_count {
             let mut cpass = encoder.begin_compute_pass(&Default::default());
             cpass.insert_debug_marker(&format!("{} dispatch", kernel));
-            cpass.set_pipeline(&pipeline);
+            cpass.set_pipeline(&pipeline.pipeline);
             let offsets = [
-                256 * 4 * dispatch_i * MAX_WORKGROUPS,
-                256 * 4 * dispatch_i * (MAX_WORKGROUPS / 256),
+                tile_size as u32 * dispatch_i * max_workgroups,
+                elem_size as u32 * dispatch_i * max_workgroups,
             ];
             
             if dispatch_i == dispatch_count - 1 {
@@ -167,11 +831,80 @@ impl Engine {
                 cpass.dispatch_workgroups(wg_remainder, 1, 1);
             } else {
                 cpass.set_bind_group(0, bind_group_max_dispatch.as_ref().unwrap(), &offsets);
-                cpass.dispatch_workgroups(MAX_WORKGROUPS, 1, 1);
+                cpass.dispatch_workgroups(max_workgroups, 1, 1);
             }
         }
+    }
 
-        self.queue.submit(Some(encoder.finish()));
+    /// Like [`Engine::dispatch_psum_kernel`], for the kernels that scan a range from its end.
+    fn dispatch_reverse_psum_kernel(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        range: BufferRange<'_>,
+        next_buffer: &wgpu::Buffer,
+        pipeline: &Pipeline,
+        kernel: &str,
+        elem_size: u64,
+    ) {
+        let starting_offset = (kernel == "psum2") as u32;
+        let tile_size = TILE_LEN * elem_size;
+        let max_workgroups = self.psum_max_workgroups(tile_size);
+        let total_wg_count = range.len.div_ceil(TILE_LEN) as u32 - starting_offset;
+        let dispatch_count = total_wg_count.div_ceil(max_workgroups);
+
+        let alignment = self.storage_alignment();
+        let (_, head) = range.aligned_start(elem_size, alignment);
+        let range_start = range.offset * elem_size;
+        let range_end = range_start + range.len * elem_size;
+        let range_params = self.range_params(head);
+
+        // every dispatch gets its own bindings, which end where its tiles start as the
+        // kernels index backwards from the end
+        for dispatch_i in 0..dispatch_count {
+            let wg_count = (total_wg_count - dispatch_i * max_workgroups).min(max_workgroups);
+            let end = range_end - (starting_offset + dispatch_i * max_workgroups) as u64 * tile_size;
+            let start = end.saturating_sub(wg_count as u64 * tile_size).max(range_start);
+
+            // the kernels skip `head` elements at the start of the binding, which only belong to
+            // the range in the last dispatch
+            let mut offset = start / alignment * alignment;
+            if start - offset < head * elem_size {
+                offset -= alignment;
+            }
+
+            let bind_group = self.device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: None,
+                layout: &pipeline.bind_group_layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                            buffer: range.buffer,
+                            offset,
+                            size: (end - offset).try_into().ok(),
+                        }),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                            buffer: next_buffer,
+                            offset: 0,
+                            size: (wg_count as u64 * elem_size).try_into().ok(),
+                        }),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 2,
+                        resource: range_params.as_entire_binding(),
+                    },
+                ],
+            });
+
+            let mut cpass = encoder.begin_compute_pass(&Default::default());
+            cpass.insert_debug_marker(&format!("{} dispatch", kernel));
+            cpass.set_pipeline(&pipeline.pipeline);
+            cpass.set_bind_group(0, &bind_group, &[0, elem_size as u32 * dispatch_i * max_workgroups]);
+            cpass.dispatch_workgroups(wg_count, 1, 1);
+        }
     }
 }
 
@@ -180,6 +913,9 @@ pub(crate) mod tests {
     use super::*;
     use crate::tests::assert_slices_eq;
 
+    use rand::Rng;
+    use rand_xoshiro::{rand_core::SeedableRng, Xoshiro256PlusPlus};
+
     pub(crate) fn prefix_sum_cpu(input: &[u32]) -> Vec<u32> {
         input
             .iter()
@@ -190,14 +926,29 @@ pub(crate) mod tests {
             .collect()
     }
 
+    pub(crate) fn exclusive_prefix_sum_cpu(input: &[u32]) -> Vec<u32> {
+        input
+            .iter()
+            .scan(0u32, |acc, x| {
+                let sum = *acc;
+                *acc += x;
+                Some(sum)
+            })
+            .collect()
+    }
+
     #[tokio::test]
     async fn trivial_input_works() -> anyhow::Result<()> {
         let engine = Engine::new().await?;
 
-        assert_slices_eq(&engine.prefix_sum(&[]).await?, &[]);
-        assert_slices_eq(&engine.prefix_sum(&[3]).await?, &[3]);
+        assert_slices_eq(&engine.prefix_sum::<u32>(&[], ScanKind::Inclusive).await?, &[]);
+        assert_slices_eq(&engine.prefix_sum(&[3], ScanKind::Inclusive).await?, &[3]);
         let input = vec![0; 1 << 20];
-        assert_slices_eq(&engine.prefix_sum(&input).await?, &input);
+        assert_slices_eq(&engine.prefix_sum(&input, ScanKind::Inclusive).await?, &input);
+
+        assert_slices_eq(&engine.prefix_sum::<u32>(&[], ScanKind::Exclusive).await?, &[]);
+        assert_slices_eq(&engine.prefix_sum(&[3], ScanKind::Exclusive).await?, &[0]);
+        assert_slices_eq(&engine.prefix_sum(&input, ScanKind::Exclusive).await?, &input);
 
         Ok(())
     }
@@ -209,7 +960,7 @@ pub(crate) mod tests {
         for n in 1..=256 {
             let input: Vec<u32> = (1..=n).collect();
             let expected: Vec<u32> = prefix_sum_cpu(&input);
-            let result = engine.prefix_sum(&input).await?;
+            let result = engine.prefix_sum(&input, ScanKind::Inclusive).await?;
 
             assert_slices_eq(&result, &expected);
         }
@@ -223,7 +974,7 @@ pub(crate) mod tests {
 
         let input: Vec<u32> = (1..=(1u32 << 11)).collect();
         let expected: Vec<u32> = prefix_sum_cpu(&input);
-        let result = engine.prefix_sum(&input).await?;
+        let result = engine.prefix_sum(&input, ScanKind::Inclusive).await?;
 
         assert_slices_eq(&result, &expected);
 
@@ -235,11 +986,432 @@ pub(crate) mod tests {
         let engine = Engine::new().await?;
 
         let input: Vec<u32> = (1..=16u32).cycle().take(1 << 23).collect();
-        assert_slices_eq(&engine.prefix_sum(&input).await?, &prefix_sum_cpu(&input));
+        assert_slices_eq(&engine.prefix_sum(&input, ScanKind::Inclusive).await?, &prefix_sum_cpu(&input));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn exclusive_sum_works() -> anyhow::Result<()> {
+        let engine = Engine::new().await?;
+
+        for n in [1, 2, 255, 256, 257, 2047, 1 << 11, 2049, 1000 * 256 + 17, 2048 * 2048 + 1, 1 << 23] {
+            let input: Vec<u32> = (1..=16u32).cycle().take(n).collect();
+            let result = engine.prefix_sum(&input, ScanKind::Exclusive).await?;
+
+            assert_slices_eq(&result, &exclusive_prefix_sum_cpu(&input));
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn signed_sum_works() -> anyhow::Result<()> {
+        let engine = Engine::new().await?;
+
+        let input: Vec<i32> = (-7..=5).cycle().take(1 << 20).collect();
+        let expected: Vec<i32> = input
+            .iter()
+            .scan(0i32, |acc, &x| {
+                *acc += x;
+                Some(*acc)
+            })
+            .collect();
+        assert_slices_eq(&engine.prefix_sum(&input, ScanKind::Inclusive).await?, &expected);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn float_sum_works() -> anyhow::Result<()> {
+        let engine = Engine::new().await?;
+
+        // halves keep every partial sum exact, whatever the order of the additions
+        let input: Vec<f32> = (0..100_000).map(|i| (i % 7) as f32 * 0.5 - 1.0).collect();
+        let expected: Vec<f32> = input
+            .iter()
+            .scan(0.0f32, |acc, &x| {
+                *acc += x;
+                Some(*acc)
+            })
+            .collect();
+        assert_slices_eq(&engine.prefix_sum(&input, ScanKind::Inclusive).await?, &expected);
+
+        let mut shifted = vec![0.0];
+        shifted.extend_from_slice(&expected[..expected.len() - 1]);
+        assert_slices_eq(&engine.prefix_sum(&input, ScanKind::Exclusive).await?, &shifted);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn wide_sum_works() -> anyhow::Result<()> {
+        let engine = Engine::new().await?;
+
+        // every addition carries into the high word
+        let input: Vec<u64> = (0..(1u64 << 18)).map(|i| u32::MAX as u64 - i % 5).collect();
+        let expected: Vec<u64> = input
+            .iter()
+            .scan(0u64, |acc, &x| {
+                *acc += x;
+                Some(*acc)
+            })
+            .collect();
+        assert_slices_eq(&engine.prefix_sum(&input, ScanKind::Inclusive).await?, &expected);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn multi_dispatch_sum_works() -> anyhow::Result<()> {
+        let mut engine = Engine::new().await?;
+
+        // wide enough elements that the bindings of a dispatch run into the default
+        // max_storage_buffer_binding_size before the dispatch runs out of workgroups
+        let input: Vec<u64> = (1..=16u64).cycle().take((1 << 24) + 1000).collect();
+        assert!(input.len() as u64 > engine.psum_max_workgroups(TILE_LEN * 8) as u64 * TILE_LEN);
+        let expected = scan_cpu(&input, 0, |a, b| a + b);
+        assert_slices_eq(&engine.prefix_sum(&input, ScanKind::Inclusive).await?, &expected);
+
+        // too large for a single pass
+        engine.scan_strategy = ScanStrategy::SinglePass;
+        assert_slices_eq(&engine.prefix_sum(&input, ScanKind::Inclusive).await?, &expected);
+
+        Ok(())
+    }
+
+    pub(crate) fn scan_cpu<T: Copy>(input: &[T], identity: T, op: impl Fn(T, T) -> T) -> Vec<T> {
+        input
+            .iter()
+            .scan(identity, |acc, &x| {
+                *acc = op(*acc, x);
+                Some(*acc)
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn scan_ops_work() -> anyhow::Result<()> {
+        let engine = Engine::new().await?;
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(0);
+
+        let input: Vec<u32> = (0..100_000).map(|_| rng.gen()).collect();
+        for (op, identity, f) in [
+            (ScanOp::Min, u32::MAX, u32::min as fn(u32, u32) -> u32),
+            (ScanOp::Max, 0, u32::max),
+            (ScanOp::And, u32::MAX, |a, b| a & b),
+            (ScanOp::Or, 0, |a, b| a | b),
+            (ScanOp::Xor, 0, |a, b| a ^ b),
+        ] {
+            println!("u32 {:?}", op);
+            let expected = scan_cpu(&input, identity, f);
+            assert_slices_eq(&engine.scan(&input, op, ScanKind::Inclusive, ScanDirection::Forward).await?, &expected);
+
+            let mut shifted = vec![identity];
+            shifted.extend_from_slice(&expected[..expected.len() - 1]);
+            assert_slices_eq(&engine.scan(&input, op, ScanKind::Exclusive, ScanDirection::Forward).await?, &shifted);
+        }
+
+        let input: Vec<i32> = (0..100_000).map(|_| rng.gen_range(-1_000_000..1_000_000)).collect();
+        assert_slices_eq(&engine.scan(&input, ScanOp::Min, ScanKind::Inclusive, ScanDirection::Forward).await?, &scan_cpu(&input, i32::MAX, i32::min));
+        assert_slices_eq(&engine.scan(&input, ScanOp::Max, ScanKind::Inclusive, ScanDirection::Forward).await?, &scan_cpu(&input, i32::MIN, i32::max));
+        assert_slices_eq(&engine.scan(&input, ScanOp::And, ScanKind::Inclusive, ScanDirection::Forward).await?, &scan_cpu(&input, -1, |a, b| a & b));
+
+        let input: Vec<f32> = (0..100_000).map(|_| rng.gen_range(-1e6..1e6)).collect();
+        assert_slices_eq(&engine.scan(&input, ScanOp::Min, ScanKind::Inclusive, ScanDirection::Forward).await?, &scan_cpu(&input, f32::INFINITY, f32::min));
+        assert_slices_eq(&engine.scan(&input, ScanOp::Max, ScanKind::Exclusive, ScanDirection::Forward).await?[1..], &scan_cpu(&input, f32::NEG_INFINITY, f32::max)[..input.len() - 1]);
+        assert!(engine.scan(&input, ScanOp::Or, ScanKind::Inclusive, ScanDirection::Forward).await.is_err());
+
+        let input: Vec<u64> = (0..100_000).map(|_| rng.gen::<u64>() >> rng.gen_range(0..64)).collect();
+        assert_slices_eq(&engine.scan(&input, ScanOp::Min, ScanKind::Inclusive, ScanDirection::Forward).await?, &scan_cpu(&input, u64::MAX, u64::min));
+        assert_slices_eq(&engine.scan(&input, ScanOp::Max, ScanKind::Inclusive, ScanDirection::Forward).await?, &scan_cpu(&input, 0, u64::max));
+        assert_slices_eq(&engine.scan(&input, ScanOp::Xor, ScanKind::Inclusive, ScanDirection::Forward).await?, &scan_cpu(&input, 0, |a, b| a ^ b));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn single_pass_matches_multi_level() -> anyhow::Result<()> {
+        let mut engine = Engine::new().await?;
+
+        // the sizes of short_sum_works through very_long_sum_works
+        let sizes = (1..=256).chain([1000 * 256 + 17, 1 << 11, 1 << 23]);
+        for n in sizes {
+            let input: Vec<u32> = (1..=16u32).cycle().take(n).collect();
+            for kind in [ScanKind::Inclusive, ScanKind::Exclusive] {
+                engine.scan_strategy = ScanStrategy::MultiLevel;
+                let multi_level = engine.scan(&input, ScanOp::Add, kind, ScanDirection::Forward).await?;
+                engine.scan_strategy = ScanStrategy::SinglePass;
+                let single_pass = engine.scan(&input, ScanOp::Add, kind, ScanDirection::Forward).await?;
+
+                assert_slices_eq(&single_pass, &multi_level);
+            }
+        }
+
+        let input: Vec<u64> = (0..(1u64 << 18)).map(|i| u32::MAX as u64 - i % 5).collect();
+        let expected = scan_cpu(&input, 0, |a, b| a + b);
+        assert_slices_eq(&engine.prefix_sum(&input, ScanKind::Inclusive).await?, &expected);
+
+        let input: Vec<i32> = (0..100_000).map(|i| (i * 7919) % 1000 - 500).collect();
+        let expected = scan_cpu(&input, i32::MAX, i32::min);
+        assert_slices_eq(&engine.scan(&input, ScanOp::Min, ScanKind::Inclusive, ScanDirection::Forward).await?, &expected);
+
+        Ok(())
+    }
+
+    fn summed_area_table_cpu<T: Copy + std::ops::Add<Output = T>>(input: &[T], dims: &[usize]) -> Vec<T> {
+        let mut table = input.to_vec();
+        let mut stride = 1;
+        for &dim in dims {
+            for i in 0..table.len() {
+                if i / stride % dim != 0 {
+                    table[i] = table[i - stride] + table[i];
+                }
+            }
+            stride *= dim;
+        }
+        table
+    }
+
+    #[tokio::test]
+    async fn summed_area_table_works() -> anyhow::Result<()> {
+        let engine = Engine::new().await?;
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(1);
+
+        // the long axes are split into chunks, and those with many chunks scan their totals in chunks too
+        let long_axes = [vec![4, 200_000], vec![300_000, 2], vec![3, 1, 100_000]];
+        for dims in [vec![1, 1], vec![7, 300], vec![3000, 5], vec![1, 1, 1], vec![16, 16, 16], vec![100, 3, 70], vec![2, 1, 3000]]
+            .into_iter()
+            .chain(long_axes)
+        {
+            let len = dims.iter().product();
+            let dims_u32: Vec<u32> = dims.iter().map(|&dim| dim as u32).collect();
+
+            let input: Vec<u32> = (0..len).map(|_| rng.gen_range(0..100)).collect();
+            let result = engine.summed_area_table(&input, &dims_u32).await?;
+            assert_slices_eq(&result, &summed_area_table_cpu(&input, &dims));
+
+            // small integers sum up exactly in any order
+            let input: Vec<f32> = (0..len).map(|_| rng.gen_range(0..4) as f32).collect();
+            let result = engine.summed_area_table(&input, &dims_u32).await?;
+            assert_slices_eq(&result, &summed_area_table_cpu(&input, &dims));
+        }
+
+        // the sum of a box from the table's corners
+        let dims = [20, 30, 40];
+        let input: Vec<u32> = (0..20 * 30 * 40).map(|_| rng.gen_range(0..10)).collect();
+        let table = engine.summed_area_table(&input, &dims).await?;
+        let at = |x: usize, y: usize, z: usize| table[(z * 30 + y) * 20 + x];
+        let (lower, upper) = ([4, 9, 14], [10, 20, 30]);
+        let box_sum = at(upper[0], upper[1], upper[2]) + at(lower[0], lower[1], upper[2]) + at(lower[0], upper[1], lower[2])
+            + at(upper[0], lower[1], lower[2])
+            - at(lower[0], upper[1], upper[2])
+            - at(upper[0], lower[1], upper[2])
+            - at(upper[0], upper[1], lower[2])
+            - at(lower[0], lower[1], lower[2]);
+        let mut expected = 0;
+        for z in lower[2] + 1..=upper[2] {
+            for y in lower[1] + 1..=upper[1] {
+                for x in lower[0] + 1..=upper[0] {
+                    expected += input[(z * 30 + y) * 20 + x];
+                }
+            }
+        }
+        assert_eq!(box_sum, expected);
+
+        assert!(engine.summed_area_table(&input, &[20, 30, 41]).await.is_err());
+        assert!(engine.summed_area_table(&input, &[20, 30, 40, 1]).await.is_err());
+
+        // a grid at an unaligned offset, with long lines along z
+        let grid: Vec<u32> = (0..3 * 2 * 200).map(|_| rng.gen_range(0..100)).collect();
+        let padded = [vec![7; 5], grid.clone(), vec![9; 3]].concat();
+        let buf = engine.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: None,
+            contents: bytemuck::cast_slice(&padded),
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+        });
+        let range = BufferRange::new(&buf, 5..5 + grid.len() as u64);
+        engine.summed_area_table_inner::<u32>(range, &[3, 2, 200])?;
+        let expected = [vec![7; 5], summed_area_table_cpu(&grid, &[3, 2, 200]), vec![9; 3]].concat();
+        assert_slices_eq(&engine.map_buffer::<u32>(&buf).await?, &expected);
+
+        let range = BufferRange::new(&buf, 10..10 + grid.len() as u64);
+        assert!(engine.summed_area_table_inner::<u32>(range, &[3, 2, 200]).is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn range_sum_works() -> anyhow::Result<()> {
+        let mut engine = Engine::new().await?;
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(0);
+
+        let input: Vec<u32> = (0..100_000).map(|_| rng.gen_range(0..100)).collect();
+        let buf = engine.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: None,
+            contents: bytemuck::cast_slice(&input),
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::COPY_SRC,
+        });
+
+        // unaligned starts, tails that aren't a multiple of 256 and several levels of tiles
+        let ranges = [0..100_000, 1..257, 3..3, 5..5000, 64..2113, 999..99_999, 99_999..100_000];
+        for strategy in [ScanStrategy::MultiLevel, ScanStrategy::SinglePass] {
+            engine.scan_strategy = strategy;
+            for range in ranges.clone() {
+                for kind in [ScanKind::Inclusive, ScanKind::Exclusive] {
+                    engine.queue.write_buffer(&buf, 0, bytemuck::cast_slice(&input));
+                    let range = range.start as usize..range.end as usize;
+                    engine.prefix_sum_range_inner::<u32>(BufferRange::new(&buf, range.start as u64..range.end as u64), kind)?;
+
+                    let mut expected = input.clone();
+                    expected[range.clone()].copy_from_slice(&match kind {
+                        ScanKind::Inclusive => prefix_sum_cpu(&input[range]),
+                        ScanKind::Exclusive => exclusive_prefix_sum_cpu(&input[range]),
+                    });
+                    assert_slices_eq(&engine.map_buffer::<u32>(&buf).await?, &expected);
+                }
+            }
+        }
+
+        // the start of a range doesn't need pipelines of its own
+        let pipelines = engine.pipelines.lock().unwrap().len();
+        engine.prefix_sum_range_inner::<u32>(BufferRange::new(&buf, 7..5000), ScanKind::Inclusive)?;
+        assert_eq!(engine.pipelines.lock().unwrap().len(), pipelines);
+
+        let input: Vec<u64> = (0..10_000).map(|_| rng.gen::<u64>() >> 8).collect();
+        let buf = engine.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: None,
+            contents: bytemuck::cast_slice(&input),
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+        });
+        engine.scan_range_inner::<u64>(BufferRange::new(&buf, 7..9000), ScanOp::Max, ScanKind::Inclusive, ScanDirection::Forward)?;
+        let mut expected = input.clone();
+        expected[7..9000].copy_from_slice(&scan_cpu(&input[7..9000], 0, u64::max));
+        assert_slices_eq(&engine.map_buffer::<u64>(&buf).await?, &expected);
+
+        assert!(engine.prefix_sum_range_inner::<u64>(BufferRange::new(&buf, 5000..10_001), ScanKind::Inclusive).is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn recorded_scans_run_in_order() -> anyhow::Result<()> {
+        let mut engine = Engine::new().await?;
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(2);
+
+        let input: Vec<u32> = (0..10_000).map(|_| rng.gen_range(0..10)).collect();
+        let buf = engine.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: None,
+            contents: bytemuck::cast_slice(&input),
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::COPY_SRC,
+        });
+
+        // the second scan sums the output of the first one
+        let expected = prefix_sum_cpu(&prefix_sum_cpu(&input[10..9000]));
+        for strategy in [ScanStrategy::MultiLevel, ScanStrategy::SinglePass] {
+            engine.scan_strategy = strategy;
+            engine.queue.write_buffer(&buf, 0, bytemuck::cast_slice(&input));
+
+            let mut encoder = engine.device.create_command_encoder(&Default::default());
+            for _ in 0..2 {
+                let range = BufferRange::new(&buf, 10..9000);
+                engine.record_scan_range::<u32>(&mut encoder, range, ScanOp::Add, ScanKind::Inclusive, ScanDirection::Forward)?;
+            }
+            engine.queue.submit(Some(encoder.finish()));
+
+            let result = engine.map_buffer::<u32>(&buf).await?;
+            assert_slices_eq(&result[10..9000], &expected);
+            assert_slices_eq(&result[..10], &input[..10]);
+        }
+
+        Ok(())
+    }
+
+    /// Scans `input` from its end on the host.
+    fn suffix_scan_cpu<T: Copy>(input: &[T], identity: T, op: impl Fn(T, T) -> T) -> Vec<T> {
+        let reversed: Vec<T> = input.iter().rev().copied().collect();
+        scan_cpu(&reversed, identity, op).into_iter().rev().collect()
+    }
+
+    #[tokio::test]
+    async fn suffix_sum_works() -> anyhow::Result<()> {
+        let mut engine = Engine::new().await?;
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(0);
+
+        for strategy in [ScanStrategy::MultiLevel, ScanStrategy::SinglePass] {
+            engine.scan_strategy = strategy;
+            for len in [1, 255, 2049, 100_000, 1 << 20] {
+                let input: Vec<u32> = (0..len).map(|_| rng.gen_range(0..100)).collect();
+                let expected = suffix_scan_cpu(&input, 0, |a, b| a + b);
+                assert_slices_eq(&engine.suffix_sum(&input, ScanKind::Inclusive).await?, &expected);
+
+                let mut shifted = expected[1..].to_vec();
+                shifted.push(0);
+                assert_slices_eq(&engine.suffix_sum(&input, ScanKind::Exclusive).await?, &shifted);
+            }
+
+            let input: Vec<i32> = (0..100_000).map(|_| rng.gen_range(-1_000_000..1_000_000)).collect();
+            let result = engine.scan(&input, ScanOp::Min, ScanKind::Inclusive, ScanDirection::Reverse).await?;
+            assert_slices_eq(&result, &suffix_scan_cpu(&input, i32::MAX, i32::min));
+
+            let input: Vec<u64> = (0..10_000).map(|_| rng.gen::<u64>() >> 24).collect();
+            let result = engine.scan(&input, ScanOp::Add, ScanKind::Inclusive, ScanDirection::Reverse).await?;
+            assert_slices_eq(&result, &suffix_scan_cpu(&input, 0, |a, b| a + b));
+        }
+
+        // a range with an unaligned start that takes several dispatches, wide enough elements
+        // that the binding size limits a dispatch as in multi_dispatch_sum_works
+        engine.scan_strategy = ScanStrategy::MultiLevel;
+        let input: Vec<u64> = (1..=16u64).cycle().take((1 << 24) + 1000).collect();
+        assert!(input.len() as u64 - 8 > engine.psum_max_workgroups(TILE_LEN * 8) as u64 * TILE_LEN);
+        let buf = engine.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: None,
+            contents: bytemuck::cast_slice(&input),
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+        });
+        let range = 3..input.len() - 5;
+        let buf_range = BufferRange::new(&buf, range.start as u64..range.end as u64);
+        engine.scan_range_inner::<u64>(buf_range, ScanOp::Add, ScanKind::Inclusive, ScanDirection::Reverse)?;
+
+        let mut expected = input.clone();
+        expected[range.clone()].copy_from_slice(&suffix_scan_cpu(&input[range], 0, |a, b| a + b));
+        assert_slices_eq(&engine.map_buffer::<u64>(&buf).await?, &expected);
 
         Ok(())
     }
 
+    /// Checks the subgroup variant of the scan kernels for `decls`, as the adapters the tests run
+    /// on might not support subgroups.
+    pub(crate) fn validate_subgroup_kernels(decls: &str, single_pass_elem_size: Option<u64>) {
+        let decls = range_decls(decls, ScanDirection::Reverse);
+        let mut sources = vec![scan_source(&decls, true, PSUM1), scan_source(&decls, true, PSUM2)];
+        if let Some(elem_size) = single_pass_elem_size {
+            sources.push(scan_source(&single_pass_decls(&decls, elem_size), true, PSUM_SINGLE_PASS));
+        }
+
+        for source in sources {
+            validate_subgroup_source(&source);
+        }
+    }
+
+    pub(crate) fn validate_subgroup_source(source: &str) {
+        let module = naga::front::wgsl::parse_str(source).unwrap_or_else(|e| panic!("{}", e.emit_to_string(source)));
+        naga::valid::Validator::new(naga::valid::ValidationFlags::all(), naga::valid::Capabilities::SUBGROUP)
+            .subgroup_stages(naga::valid::ShaderStages::COMPUTE)
+            .subgroup_operations(naga::valid::SubgroupOperationSet::all())
+            .validate(&module)
+            .unwrap_or_else(|e| panic!("{}", e.emit_to_string(source)));
+    }
+
+    #[test]
+    fn subgroup_kernels_validate() {
+        let decls = scan_decls::<u32>(ScanOp::Add).unwrap() + PLAIN_ELEM + &subgroup_decls(Some("subgroupInclusiveAdd"), "subgroupShuffleUp(e, delta)");
+        validate_subgroup_kernels(&decls, Some(4));
+
+        let decls = scan_decls::<u64>(ScanOp::Min).unwrap() + PLAIN_ELEM + &subgroup_decls(None, "subgroupShuffleUp(e, delta)");
+        validate_subgroup_kernels(&decls, Some(8));
+    }
+
     #[ignore]
     #[tokio::test]
     async fn very_very_long_sum_works() -> anyhow::Result<()> {
@@ -247,7 +1419,7 @@ pub(crate) mod tests {
 
         let input: Vec<u32> = (1..=16u32).cycle().take(1 << 25).collect();
         let expected: Vec<u32> = prefix_sum_cpu(&input);
-        let result = engine.prefix_sum(&input).await?;
+        let result = engine.prefix_sum(&input, ScanKind::Inclusive).await?;
 
         assert_slices_eq(&result, &expected);
 
@@ -0,0 +1,255 @@
+use wgpu::util::DeviceExt;
+
+use crate::pipeline::{STORAGE, STORAGE_READ, UNIFORM};
+use crate::prefix_sum::{scan_source, subgroup_decls};
+use crate::{Engine, ScanKind};
+
+const RADIX_SORT: &str = include_str!("kernels/radix_sort.wgsl");
+
+const RADIX_BITS: u32 = 4;
+const RADIX: u64 = 1 << RADIX_BITS;
+const TILE_LEN: u64 = 256;
+const MAX_WORKGROUPS: u64 = 65535;
+
+/// The tiles rank their keys by scanning a one-hot count of their digits, a byte per digit.
+const DIGIT_ELEM: &str = "
+    alias Elem = vec4u;
+    fn identity() -> Elem { return vec4u(0u); }
+    fn combine(a: Elem, b: Elem) -> Elem { return a + b; }
+";
+
+const PAIRS_DECLS: &str = "
+    @group(0) @binding(4)
+    var<storage, read> values_in: array<u32>;
+    @group(0) @binding(5)
+    var<storage, read_write> values_out: array<u32>;
+    fn move_value(src: u32, dst: u32) { values_out[dst] = values_in[src]; }
+";
+
+const KEYS_DECLS: &str = "fn move_value(src: u32, dst: u32) {}\n";
+
+impl Engine {
+    /// Sorts `keys` along with their `values`, keeping equal keys in their original order.
+    pub async fn sort_pairs(&self, keys: &[u32], values: &[u32]) -> anyhow::Result<(Vec<u32>, Vec<u32>)> {
+        anyhow::ensure!(
+            keys.len() == values.len(),
+            "expected one value per key, got {} values for {} keys",
+            values.len(),
+            keys.len(),
+        );
+
+        if keys.is_empty() {
+            return Ok((vec![], vec![]));
+        }
+
+        let keys_buf = self.sort_input("radix sort keys", keys);
+        let values_buf = self.sort_input("radix sort values", values);
+        self.sort_pairs_inner(&keys_buf, Some(&values_buf))?;
+
+        Ok((self.map_buffer(&keys_buf).await?, self.map_buffer(&values_buf).await?))
+    }
+
+    /// Sorts `keys` in ascending order.
+    pub async fn sort_keys(&self, keys: &[u32]) -> anyhow::Result<Vec<u32>> {
+        if keys.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let keys_buf = self.sort_input("radix sort keys", keys);
+        self.sort_pairs_inner(&keys_buf, None)?;
+
+        self.map_buffer(&keys_buf).await
+    }
+
+    /// Stably sorts a buffer of `u32` keys in place, along with a buffer of `u32` values if
+    /// given. Each of the 4-bit digits takes a counting, a scanning and a scattering pass.
+    pub fn sort_pairs_inner(&self, keys: &wgpu::Buffer, values: Option<&wgpu::Buffer>) -> anyhow::Result<()> {
+        let len = keys.size() / 4;
+        anyhow::ensure!(len > 0, "cannot sort an empty buffer");
+        if let Some(values) = values {
+            anyhow::ensure!(
+                values.size() == keys.size(),
+                "expected one value per key, got {} bytes of values for {} keys",
+                values.size(),
+                len,
+            );
+        }
+
+        let mut bindings = vec![UNIFORM, STORAGE_READ, STORAGE, STORAGE];
+        let mut decls = DIGIT_ELEM.to_string();
+        if self.capabilities.subgroups {
+            decls += &subgroup_decls(Some("subgroupInclusiveAdd"), "subgroupShuffleUp(e, delta)");
+        }
+        let specialization = if values.is_some() {
+            bindings.extend([STORAGE_READ, STORAGE]);
+            decls += PAIRS_DECLS;
+            "pairs"
+        } else {
+            decls += KEYS_DECLS;
+            "keys"
+        };
+
+        let tiles = len.div_ceil(TILE_LEN);
+        let counts = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("radix sort counts"),
+            size: RADIX * tiles * 4,
+            usage: wgpu::BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+
+        // the passes ping-pong between the buffers, ending in the original ones as their number is even
+        let temp_keys = self.sort_temp(keys);
+        let temp_values = values.map(|values| self.sort_temp(values));
+
+        for pass in 0..u32::BITS / RADIX_BITS {
+            let params = self
+                .device
+                .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label: Some("radix sort params"),
+                    contents: bytemuck::cast_slice(&[pass * RADIX_BITS, 0, 0, 0]),
+                    usage: wgpu::BufferUsages::UNIFORM,
+                });
+
+            let (keys_in, keys_out) = if pass % 2 == 0 { (keys, &temp_keys) } else { (&temp_keys, keys) };
+            let mut bufs = vec![&params, keys_in, &counts, keys_out];
+            if let (Some(values), Some(temp_values)) = (values, &temp_values) {
+                bufs.extend(if pass % 2 == 0 { [values, temp_values] } else { [temp_values, values] });
+            }
+
+            self.dispatch_radix_sort_kernel(&bufs, &bindings, specialization, &decls, "count", tiles);
+            self.prefix_sum_inner::<u32>(&counts, ScanKind::Exclusive);
+            self.dispatch_radix_sort_kernel(&bufs, &bindings, specialization, &decls, "scatter", tiles);
+        }
+
+        Ok(())
+    }
+
+    fn sort_input(&self, label: &str, input: &[u32]) -> wgpu::Buffer {
+        self.device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some(label),
+                contents: bytemuck::cast_slice(input),
+                usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+            })
+    }
+
+    fn sort_temp(&self, buf: &wgpu::Buffer) -> wgpu::Buffer {
+        self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("radix sort temp"),
+            size: buf.size(),
+            usage: wgpu::BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        })
+    }
+
+    fn dispatch_radix_sort_kernel(
+        &self,
+        bufs: &[&wgpu::Buffer],
+        bindings: &[wgpu::BindingType],
+        specialization: &str,
+        decls: &str,
+        entry_point: &str,
+        tiles: u64,
+    ) {
+        let pipeline = self.specialized_pipeline(
+            "radix_sort",
+            specialization,
+            || scan_source(decls, self.capabilities.subgroups, RADIX_SORT),
+            entry_point,
+            bindings,
+        );
+        let bind_group = self.bind_buffers(&pipeline, bufs);
+
+        let mut encoder = self.device.create_command_encoder(&Default::default());
+        {
+            let mut cpass = encoder.begin_compute_pass(&Default::default());
+            cpass.insert_debug_marker(&format!("radix sort {} dispatch", entry_point));
+            cpass.set_pipeline(&pipeline.pipeline);
+            cpass.set_bind_group(0, &bind_group, &[]);
+            cpass.dispatch_workgroups(
+                tiles.min(MAX_WORKGROUPS) as u32,
+                tiles.div_ceil(MAX_WORKGROUPS) as u32,
+                1,
+            );
+        }
+
+        self.queue.submit(Some(encoder.finish()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prefix_sum::tests::validate_subgroup_source;
+    use crate::tests::assert_slices_eq;
+
+    use rand::Rng;
+    use rand_xoshiro::{rand_core::SeedableRng, Xoshiro256PlusPlus};
+
+    /// Sorts `keys` on the device, with their indices as values to check that equal keys keep
+    /// their order.
+    async fn check_sort(engine: &Engine, keys: &[u32]) -> anyhow::Result<()> {
+        let indices: Vec<u32> = (0..keys.len() as u32).collect();
+        let mut expected: Vec<(u32, u32)> = keys.iter().copied().zip(indices.iter().copied()).collect();
+        expected.sort_by_key(|&(key, _)| key);
+        let (expected_keys, expected_values): (Vec<u32>, Vec<u32>) = expected.into_iter().unzip();
+
+        let (sorted_keys, sorted_values) = engine.sort_pairs(keys, &indices).await?;
+        assert_slices_eq(&sorted_keys, &expected_keys);
+        assert_slices_eq(&sorted_values, &expected_values);
+
+        let mut sorted = keys.to_vec();
+        sorted.sort();
+        assert_slices_eq(&engine.sort_keys(keys).await?, &sorted);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sort_random_keys_works() -> anyhow::Result<()> {
+        let engine = Engine::new().await?;
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(0);
+
+        for len in [1, 255, 257, 100_000, 1 << 20] {
+            let keys: Vec<u32> = (0..len).map(|_| rng.gen()).collect();
+            check_sort(&engine, &keys).await?;
+
+            // many duplicates
+            let keys: Vec<u32> = (0..len).map(|_| rng.gen_range(0..100)).collect();
+            check_sort(&engine, &keys).await?;
+        }
+
+        assert!(engine.sort_pairs(&[1, 2], &[1]).await.is_err());
+        assert_eq!(engine.sort_keys(&[]).await?, Vec::<u32>::new());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sort_adversarial_keys_works() -> anyhow::Result<()> {
+        let engine = Engine::new().await?;
+        let len = 100_000u32;
+
+        let inputs: [Vec<u32>; 6] = [
+            vec![7; len as usize],
+            (0..len).collect(),
+            (0..len).rev().collect(),
+            (0..len).map(|i| i << 20).collect(),
+            (0..len).map(|i| if i % 3 == 0 { u32::MAX } else { 0 }).collect(),
+            // every tile holds a single digit, differing between neighboring tiles
+            (0..len).map(|i| (i / 256 % 16) * 0x1111_1111).collect(),
+        ];
+        for keys in inputs {
+            check_sort(&engine, &keys).await?;
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn radix_sort_subgroup_kernels_validate() {
+        let decls = DIGIT_ELEM.to_string() + &subgroup_decls(Some("subgroupInclusiveAdd"), "subgroupShuffleUp(e, delta)");
+        validate_subgroup_source(&scan_source(&(decls.clone() + PAIRS_DECLS), true, RADIX_SORT));
+        validate_subgroup_source(&scan_source(&(decls + KEYS_DECLS), true, RADIX_SORT));
+    }
+}
@@ -0,0 +1,327 @@
+use wgpu::util::DeviceExt;
+
+use crate::pipeline::{STORAGE, STORAGE_READ};
+use crate::prefix_sum::{scan_decls, scan_source, subgroup_decls, TILE_LEN};
+use crate::{Engine, ScanElement, ScanOp};
+
+const REDUCE: &str = include_str!("kernels/reduce.wgsl");
+
+const PLAIN_TO_ELEM: &str = "fn to_elem(value: Value, index: u32) -> Elem { return value; }\n";
+
+/// Pairs each value with its index. Of two equal values the first one wins, and `combine_values`
+/// is the min or max of the values.
+const ARG_ELEM: &str = "
+    struct Elem {
+        index: u32,
+        value: Value,
+    }
+    fn identity() -> Elem { return Elem(0xffffffffu, identity_value()); }
+    fn combine(a: Elem, b: Elem) -> Elem {
+        if all(a.value == b.value) {
+            if a.index < b.index {
+                return a;
+            }
+            return b;
+        }
+        if all(combine_values(a.value, b.value) == a.value) {
+            return a;
+        }
+        return b;
+    }
+    fn to_elem(value: Value, index: u32) -> Elem { return Elem(index, value); }
+";
+
+/// Shuffles an argmin or argmax `Elem` field by field.
+const ARG_SHUFFLE_UP: &str = "Elem(subgroupShuffleUp(e.index, delta), subgroupShuffleUp(e.value, delta))";
+
+impl Engine {
+    /// Combines all of `input` with `op`, failing if it's empty.
+    pub async fn reduce<T: ScanElement>(&self, input: &[T], op: ScanOp) -> anyhow::Result<T> {
+        let result = self.reduce_inner::<T>(&self.reduce_input(input)?, op)?;
+
+        Ok(self.map_buffer::<T>(&result).await?[0])
+    }
+
+    /// Combines a buffer of `T`s with `op`, into a new buffer holding a single `T`. The buffer is
+    /// bound whole, so it must not exceed `max_storage_buffer_binding_size`.
+    pub fn reduce_inner<T: ScanElement>(&self, buf: &wgpu::Buffer, op: ScanOp) -> anyhow::Result<wgpu::Buffer> {
+        let mut decls = self.plain_elem_decls::<T>(op)?;
+        decls += PLAIN_TO_ELEM;
+        let specialization = format!("{}, {:?}", std::any::type_name::<T>(), op);
+
+        let elem_size = std::mem::size_of::<T>() as u64;
+        self.reduce_levels(buf, &specialization, &decls, elem_size, elem_size)
+    }
+
+    /// Returns the index of the first smallest value of `input`, failing if it's empty.
+    pub async fn argmin<T: ScanElement>(&self, input: &[T]) -> anyhow::Result<u32> {
+        let result = self.argmin_inner::<T>(&self.reduce_input(input)?)?;
+
+        Ok(self.map_buffer::<u32>(&result).await?[0])
+    }
+
+    /// Returns the index of the first largest value of `input`, failing if it's empty.
+    pub async fn argmax<T: ScanElement>(&self, input: &[T]) -> anyhow::Result<u32> {
+        let result = self.argmax_inner::<T>(&self.reduce_input(input)?)?;
+
+        Ok(self.map_buffer::<u32>(&result).await?[0])
+    }
+
+    /// Finds the first smallest value of a buffer of `T`s. The new buffer holds its index as a
+    /// `u32`, followed by the value at the alignment of `T`. Like [`Engine::reduce_inner`], fails
+    /// for buffers larger than `max_storage_buffer_binding_size`.
+    pub fn argmin_inner<T: ScanElement>(&self, buf: &wgpu::Buffer) -> anyhow::Result<wgpu::Buffer> {
+        self.arg_reduce_inner::<T>(buf, ScanOp::Min)
+    }
+
+    /// Like [`Engine::argmin_inner`], for the first largest value, with the same limit on the
+    /// buffer size.
+    pub fn argmax_inner<T: ScanElement>(&self, buf: &wgpu::Buffer) -> anyhow::Result<wgpu::Buffer> {
+        self.arg_reduce_inner::<T>(buf, ScanOp::Max)
+    }
+
+    fn arg_reduce_inner<T: ScanElement>(&self, buf: &wgpu::Buffer, op: ScanOp) -> anyhow::Result<wgpu::Buffer> {
+        let mut decls = scan_decls::<T>(op)? + ARG_ELEM;
+        if self.capabilities.subgroups {
+            decls += &subgroup_decls(None, ARG_SHUFFLE_UP);
+        }
+        let specialization = format!("arg, {}, {:?}", std::any::type_name::<T>(), op);
+
+        // the index is padded to the alignment of the value
+        let value_size = std::mem::size_of::<T>() as u64;
+        self.reduce_levels(buf, &specialization, &decls, value_size, 2 * value_size)
+    }
+
+    fn reduce_input<T: ScanElement>(&self, input: &[T]) -> anyhow::Result<wgpu::Buffer> {
+        anyhow::ensure!(!input.is_empty(), "cannot reduce an empty input");
+
+        Ok(self
+            .device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("reduce input"),
+                contents: bytemuck::cast_slice(input),
+                usage: wgpu::BufferUsages::STORAGE,
+            }))
+    }
+
+    /// Reduces every tile of a buffer of `Value`s to an `Elem` of `elem_size` bytes, and then the
+    /// tiles' totals until a single one is left.
+    fn reduce_levels(
+        &self,
+        buf: &wgpu::Buffer,
+        specialization: &str,
+        decls: &str,
+        value_size: u64,
+        elem_size: u64,
+    ) -> anyhow::Result<wgpu::Buffer> {
+        anyhow::ensure!(buf.size() > 0, "cannot reduce an empty buffer");
+        let max_binding_size = self.device.limits().max_storage_buffer_binding_size as u64;
+        anyhow::ensure!(
+            buf.size() <= max_binding_size,
+            "cannot reduce a buffer of {} bytes, more than the {} byte binding limit",
+            buf.size(),
+            max_binding_size,
+        );
+
+        let mut tiles = (buf.size() / value_size).div_ceil(TILE_LEN);
+        let mut entry_point = "main";
+        let mut previous_totals: Option<wgpu::Buffer> = None;
+
+        // stands in for the partials of the first level, as the input can be smaller than an Elem
+        let no_partials = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("reduce no partials"),
+            size: elem_size,
+            usage: wgpu::BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+
+        loop {
+            let (input, partials) = match &previous_totals {
+                Some(totals) => (totals, totals),
+                None => (buf, &no_partials),
+            };
+            let totals = self.device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("reduce totals"),
+                size: tiles * elem_size,
+                usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+                mapped_at_creation: false,
+            });
+
+            // the totals of the previous level are bound twice, main_partials reads them as Elems
+            self.dispatch_reduce_kernel(&[input, partials, &totals], specialization, decls, entry_point, tiles);
+
+            if tiles == 1 {
+                return Ok(totals);
+            }
+
+            tiles = tiles.div_ceil(TILE_LEN);
+            entry_point = "main_partials";
+            previous_totals = Some(totals);
+        }
+    }
+
+    fn dispatch_reduce_kernel(
+        &self,
+        bufs: &[&wgpu::Buffer],
+        specialization: &str,
+        decls: &str,
+        entry_point: &str,
+        tiles: u64,
+    ) {
+        const MAX_WORKGROUPS: u64 = 65535;
+
+        let pipeline = self.specialized_pipeline(
+            "reduce",
+            specialization,
+            || scan_source(decls, self.capabilities.subgroups, REDUCE),
+            entry_point,
+            &[STORAGE_READ, STORAGE_READ, STORAGE],
+        );
+        let bind_group = self.bind_buffers(&pipeline, bufs);
+
+        let mut encoder = self.device.create_command_encoder(&Default::default());
+        {
+            let mut cpass = encoder.begin_compute_pass(&Default::default());
+            cpass.insert_debug_marker(&format!("reduce {} dispatch", entry_point));
+            cpass.set_pipeline(&pipeline.pipeline);
+            cpass.set_bind_group(0, &bind_group, &[]);
+            cpass.dispatch_workgroups(
+                tiles.min(MAX_WORKGROUPS) as u32,
+                tiles.div_ceil(MAX_WORKGROUPS) as u32,
+                1,
+            );
+        }
+
+        self.queue.submit(Some(encoder.finish()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prefix_sum::tests::validate_subgroup_source;
+    use crate::prefix_sum::PLAIN_ELEM;
+
+    use rand::Rng;
+    use rand_xoshiro::{rand_core::SeedableRng, Xoshiro256PlusPlus};
+
+    #[tokio::test]
+    async fn reduce_works() -> anyhow::Result<()> {
+        let engine = Engine::new().await?;
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(0);
+
+        // one, two and three levels of tiles
+        for len in [1, 255, 2049, 100_000, 1 << 23] {
+            let input: Vec<u32> = (0..len).map(|_| rng.gen_range(0..1000)).collect();
+            assert_eq!(engine.reduce(&input, ScanOp::Add).await?, input.iter().sum::<u32>());
+            assert_eq!(engine.reduce(&input, ScanOp::Min).await?, *input.iter().min().unwrap());
+            assert_eq!(engine.reduce(&input, ScanOp::Xor).await?, input.iter().fold(0, |a, b| a ^ b));
+        }
+
+        let input: Vec<i32> = (0..100_000).map(|_| rng.gen_range(-1_000_000..1_000_000)).collect();
+        assert_eq!(engine.reduce(&input, ScanOp::Max).await?, *input.iter().max().unwrap());
+
+        let input: Vec<f32> = (0..100_000).map(|_| rng.gen_range(-1e6..1e6)).collect();
+        assert_eq!(engine.reduce(&input, ScanOp::Min).await?, input.iter().copied().fold(f32::INFINITY, f32::min));
+
+        let input: Vec<u64> = (0..100_000).map(|_| rng.gen::<u32>() as u64 * 1000).collect();
+        assert_eq!(engine.reduce(&input, ScanOp::Add).await?, input.iter().sum::<u64>());
+
+        assert!(engine.reduce::<u32>(&[], ScanOp::Add).await.is_err());
+        assert!(engine.reduce(&[1.0f32], ScanOp::And).await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn argmin_argmax_work() -> anyhow::Result<()> {
+        let engine = Engine::new().await?;
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(1);
+
+        // few distinct values, so that the first of several extremes has to be found
+        for len in [1, 300, 5000, 1 << 20] {
+            let input: Vec<u32> = (0..len).map(|_| rng.gen_range(10..20)).collect();
+            let min = *input.iter().min().unwrap();
+            let max = *input.iter().max().unwrap();
+            assert_eq!(engine.argmin(&input).await? as usize, input.iter().position(|&x| x == min).unwrap());
+            assert_eq!(engine.argmax(&input).await? as usize, input.iter().position(|&x| x == max).unwrap());
+        }
+
+        let mut input = vec![u32::MAX; 10_000];
+        input[7777] = 3;
+        input[8888] = 3;
+        assert_eq!(engine.argmin(&input).await?, 7777);
+        assert_eq!(engine.argmax(&input).await?, 0);
+
+        let input: Vec<f32> = (0..100_000).map(|_| rng.gen_range(-1e6..1e6)).collect();
+        let (expected, _) = input.iter().enumerate().fold((0, f32::INFINITY), |acc, (i, &x)| if x < acc.1 { (i, x) } else { acc });
+        assert_eq!(engine.argmin(&input).await? as usize, expected);
+
+        let input: Vec<u64> = (0..100_000).map(|i| (i % 1000) << 40).collect();
+        assert_eq!(engine.argmax(&input).await?, 999);
+
+        // a single value is smaller than an index and value
+        assert_eq!(engine.argmin(&[5u64]).await?, 0);
+        assert_eq!(engine.argmax(&[5u64]).await?, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn reduce_subgroup_kernels_validate() {
+        let decls = scan_decls::<f32>(ScanOp::Add).unwrap() + PLAIN_ELEM + PLAIN_TO_ELEM + &subgroup_decls(Some("subgroupInclusiveAdd"), "subgroupShuffleUp(e, delta)");
+        validate_subgroup_source(&scan_source(&decls, true, REDUCE));
+
+        let decls = scan_decls::<u64>(ScanOp::Max).unwrap() + ARG_ELEM + &subgroup_decls(None, ARG_SHUFFLE_UP);
+        validate_subgroup_source(&scan_source(&decls, true, REDUCE));
+    }
+
+    #[tokio::test]
+    async fn reduce_leaves_result_on_device() -> anyhow::Result<()> {
+        let engine = Engine::new().await?;
+
+        let input: Vec<i32> = (0..100_000).map(|i| (i * 7919) % 10_007 - 5000).collect();
+        let buf = engine.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: None,
+            contents: bytemuck::cast_slice(&input),
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+
+        let sum = engine.reduce_inner::<i32>(&buf, ScanOp::Add)?;
+        assert_eq!(engine.map_buffer::<i32>(&sum).await?, [input.iter().sum::<i32>()]);
+
+        // index and value
+        let max = engine.argmax_inner::<i32>(&buf)?;
+        let expected = input.iter().enumerate().max_by_key(|&(i, &x)| (x, std::cmp::Reverse(i))).unwrap();
+        assert_eq!(engine.map_buffer::<i32>(&max).await?, [expected.0 as i32, *expected.1]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reduce_rejects_buffers_past_the_binding_limit() -> anyhow::Result<()> {
+        let engine = Engine::new().await?;
+        let max_binding_size = engine.device.limits().max_storage_buffer_binding_size as u64;
+
+        let buf = engine.device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size: max_binding_size,
+            usage: wgpu::BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+        let sum = engine.reduce_inner::<u32>(&buf, ScanOp::Add)?;
+        assert_eq!(engine.map_buffer::<u32>(&sum).await?, [0]);
+
+        let buf = engine.device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size: max_binding_size + 4,
+            usage: wgpu::BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+        assert!(engine.reduce_inner::<u32>(&buf, ScanOp::Add).is_err());
+        assert!(engine.argmin_inner::<u32>(&buf).is_err());
+        assert!(engine.argmax_inner::<u32>(&buf).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,275 @@
+use std::ops::Range;
+
+use wgpu::util::DeviceExt;
+
+use crate::{Engine, ScanElement, ScanKind, ScanOp, Segments};
+
+/// Independent arrays scanned together by [`Engine::scan_batch_inner`].
+#[derive(Copy, Clone, Debug)]
+pub enum ScanBatch<'a> {
+    /// Ranges of elements of buffers that are usable as copy sources and destinations. Ranges
+    /// of the same buffer must not overlap.
+    Ranges(&'a [(&'a wgpu::Buffer, Range<u64>)]),
+    /// The consecutive rows of `row_len` elements that fill `buf`.
+    Rows { buf: &'a wgpu::Buffer, row_len: u64 },
+}
+
+impl Engine {
+    /// Scans each of `arrays` independently, see [`Engine::scan`].
+    pub async fn scan_batch<T: ScanElement>(
+        &self,
+        arrays: &[&[T]],
+        op: ScanOp,
+        kind: ScanKind,
+    ) -> anyhow::Result<Vec<Vec<T>>> {
+        let input = arrays.concat();
+        if input.is_empty() {
+            return Ok(arrays.iter().map(|_| vec![]).collect());
+        }
+
+        let mut starts = vec![];
+        let mut start = 0;
+        for array in arrays {
+            if !array.is_empty() {
+                starts.push(start as u64);
+            }
+            start += array.len();
+        }
+
+        let buf = self.scan_batch_input(&input);
+        let mut encoder = self.device.create_command_encoder(&Default::default());
+        self.record_batch::<T>(&mut encoder, &buf, &starts, op, kind)?;
+        self.queue.submit(Some(encoder.finish()));
+
+        let mut output = self.map_buffer::<T>(&buf).await?.into_iter();
+        Ok(arrays.iter().map(|array| output.by_ref().take(array.len()).collect()).collect())
+    }
+
+    /// Scans each row of `row_len` elements of `input` independently, see [`Engine::scan`].
+    pub async fn scan_rows<T: ScanElement>(
+        &self,
+        input: &[T],
+        row_len: usize,
+        op: ScanOp,
+        kind: ScanKind,
+    ) -> anyhow::Result<Vec<T>> {
+        anyhow::ensure!(
+            row_len > 0 && input.len().is_multiple_of(row_len),
+            "{} elements don't form rows of {}",
+            input.len(),
+            row_len,
+        );
+        if input.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let buf = self.scan_batch_input(input);
+        self.scan_batch_inner::<T>(ScanBatch::Rows { buf: &buf, row_len: row_len as u64 }, op, kind)?;
+
+        self.map_buffer(&buf).await
+    }
+
+    /// Scans every array of `batch` of `T`s in place, with a single multi-level segmented scan
+    /// and a single submission however many arrays there are.
+    pub fn scan_batch_inner<T: ScanElement>(&self, batch: ScanBatch<'_>, op: ScanOp, kind: ScanKind) -> anyhow::Result<()> {
+        let elem_size = std::mem::size_of::<T>() as u64;
+        let mut encoder = self.device.create_command_encoder(&Default::default());
+
+        match batch {
+            ScanBatch::Rows { buf, row_len } => {
+                let len = buf.size() / elem_size;
+                anyhow::ensure!(
+                    row_len > 0 && len.is_multiple_of(row_len),
+                    "{} elements don't form rows of {}",
+                    len,
+                    row_len,
+                );
+                if len == 0 {
+                    return Ok(());
+                }
+
+                let starts: Vec<u64> = (0..len).step_by(row_len as usize).collect();
+                self.record_batch::<T>(&mut encoder, buf, &starts, op, kind)?;
+            }
+            ScanBatch::Ranges(ranges) => {
+                for (buf, range) in ranges {
+                    anyhow::ensure!(
+                        range.start <= range.end && range.end * elem_size <= buf.size(),
+                        "range {:?} is out of bounds of a buffer of {} elements",
+                        range,
+                        buf.size() / elem_size,
+                    );
+                }
+
+                // the ranges are gathered into a single buffer and scattered back after the scan
+                let mut starts = vec![];
+                let mut len = 0;
+                for (_, range) in ranges.iter().filter(|(_, range)| !range.is_empty()) {
+                    starts.push(len);
+                    len += range.end - range.start;
+                }
+                if len == 0 {
+                    return Ok(());
+                }
+
+                let packed = self.device.create_buffer(&wgpu::BufferDescriptor {
+                    label: Some("scan batch packed"),
+                    size: len * elem_size,
+                    usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::COPY_SRC,
+                    mapped_at_creation: false,
+                });
+
+                let non_empty = || ranges.iter().filter(|(_, range)| !range.is_empty()).zip(&starts);
+                for ((buf, range), &start) in non_empty() {
+                    let size = (range.end - range.start) * elem_size;
+                    encoder.copy_buffer_to_buffer(buf, range.start * elem_size, &packed, start * elem_size, size);
+                }
+
+                self.record_batch::<T>(&mut encoder, &packed, &starts, op, kind)?;
+
+                for ((buf, range), &start) in non_empty() {
+                    let size = (range.end - range.start) * elem_size;
+                    encoder.copy_buffer_to_buffer(&packed, start * elem_size, buf, range.start * elem_size, size);
+                }
+            }
+        }
+
+        self.queue.submit(Some(encoder.finish()));
+
+        Ok(())
+    }
+
+    fn scan_batch_input<T: ScanElement>(&self, input: &[T]) -> wgpu::Buffer {
+        self.device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("scan batch input"),
+                contents: bytemuck::cast_slice(input),
+                usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+            })
+    }
+
+    /// Records the scan of the arrays of `buf` that begin at `starts`.
+    fn record_batch<T: ScanElement>(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        buf: &wgpu::Buffer,
+        starts: &[u64],
+        op: ScanOp,
+        kind: ScanKind,
+    ) -> anyhow::Result<()> {
+        let starts: Vec<u32> = starts.iter().map(|&start| start as u32).collect();
+        let offsets = self
+            .device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("scan batch offsets"),
+                contents: bytemuck::cast_slice(&starts),
+                usage: wgpu::BufferUsages::STORAGE,
+            });
+
+        self.record_segmented_scan::<T>(encoder, buf, Segments::Offsets(&offsets), op, kind)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prefix_sum::tests::{exclusive_prefix_sum_cpu, prefix_sum_cpu, scan_cpu};
+    use crate::tests::assert_slices_eq;
+
+    use rand::Rng;
+    use rand_xoshiro::{rand_core::SeedableRng, Xoshiro256PlusPlus};
+
+    #[tokio::test]
+    async fn scan_batch_works() -> anyhow::Result<()> {
+        let engine = Engine::new().await?;
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(0);
+
+        // many small arrays, some of them empty, and a few spanning several tiles
+        let mut arrays: Vec<Vec<u32>> = (0..500)
+            .map(|_| {
+                let len = rng.gen_range(0..300);
+                (0..len).map(|_| rng.gen_range(0..100)).collect()
+            })
+            .collect();
+        arrays.push((0..100_000).map(|_| rng.gen_range(0..100)).collect());
+        arrays.insert(0, vec![]);
+        let slices: Vec<&[u32]> = arrays.iter().map(|array| &array[..]).collect();
+
+        let result = engine.scan_batch(&slices, ScanOp::Add, ScanKind::Inclusive).await?;
+        assert_eq!(result.len(), arrays.len());
+        for (result, array) in std::iter::zip(&result, &arrays) {
+            assert_slices_eq(result, &prefix_sum_cpu(array));
+        }
+
+        let result = engine.scan_batch(&slices, ScanOp::Add, ScanKind::Exclusive).await?;
+        for (result, array) in std::iter::zip(&result, &arrays) {
+            assert_slices_eq(result, &exclusive_prefix_sum_cpu(array));
+        }
+
+        let result = engine.scan_batch(&slices, ScanOp::Min, ScanKind::Inclusive).await?;
+        for (result, array) in std::iter::zip(&result, &arrays) {
+            assert_slices_eq(result, &scan_cpu(array, u32::MAX, u32::min));
+        }
+
+        assert_eq!(engine.scan_batch::<u32>(&[&[], &[]], ScanOp::Add, ScanKind::Inclusive).await?.len(), 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn scan_rows_works() -> anyhow::Result<()> {
+        let engine = Engine::new().await?;
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(1);
+
+        for (rows, row_len) in [(1, 1), (1000, 1), (300, 100), (64, 3000)] {
+            let input: Vec<i32> = (0..rows * row_len).map(|_| rng.gen_range(-100..100)).collect();
+            let expected: Vec<i32> = input.chunks(row_len).flat_map(|row| scan_cpu(row, 0, |a, b| a + b)).collect();
+
+            let result = engine.scan_rows(&input, row_len, ScanOp::Add, ScanKind::Inclusive).await?;
+            assert_slices_eq(&result, &expected);
+        }
+
+        assert!(engine.scan_rows(&[1u32; 10], 3, ScanOp::Add, ScanKind::Inclusive).await.is_err());
+        assert!(engine.scan_rows(&[1u32; 10], 0, ScanOp::Add, ScanKind::Inclusive).await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn scan_batch_ranges_work() -> anyhow::Result<()> {
+        let engine = Engine::new().await?;
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(2);
+
+        let inputs: Vec<Vec<u32>> = (0..2).map(|_| (0..5000).map(|_| rng.gen_range(0..100)).collect()).collect();
+        let bufs: Vec<wgpu::Buffer> = inputs
+            .iter()
+            .map(|input| {
+                engine.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label: None,
+                    contents: bytemuck::cast_slice(input),
+                    usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::COPY_SRC,
+                })
+            })
+            .collect();
+
+        let ranges = [(0, 10..300), (0, 300..301), (1, 0..4000), (0, 1000..1000), (0, 2000..5000), (1, 4999..5000)];
+        let batch: Vec<(&wgpu::Buffer, Range<u64>)> = ranges.iter().map(|(i, range)| (&bufs[*i], range.clone())).collect();
+        engine.scan_batch_inner::<u32>(ScanBatch::Ranges(&batch), ScanOp::Add, ScanKind::Exclusive)?;
+
+        // elements outside of the ranges are left alone
+        let mut expected = inputs.clone();
+        for (i, range) in ranges {
+            let range = range.start as usize..range.end as usize;
+            let scanned = exclusive_prefix_sum_cpu(&expected[i][range.clone()]);
+            expected[i][range].copy_from_slice(&scanned);
+        }
+        for (buf, expected) in std::iter::zip(&bufs, &expected) {
+            assert_slices_eq(&engine.map_buffer::<u32>(buf).await?, expected);
+        }
+
+        let batch = [(&bufs[0], 4000..5001)];
+        assert!(engine.scan_batch_inner::<u32>(ScanBatch::Ranges(&batch), ScanOp::Add, ScanKind::Inclusive).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,372 @@
+use wgpu::util::DeviceExt;
+
+use crate::pipeline::{STORAGE, STORAGE_READ};
+use crate::prefix_sum::{scan_decls, subgroup_decls, BufferRange, ElemDecls};
+use crate::{Engine, ScanDirection, ScanElement, ScanKind, ScanOp};
+
+const SEGMENTED_SCAN: &str = include_str!("kernels/segmented_scan.wgsl");
+
+/// Each value is scanned along with the head flag of its segment, so that the multi-level scan
+/// restarts at every head no matter which workgroup it falls in.
+const SEGMENTED_ELEM: &str = "
+    struct Elem {
+        head: u32,
+        value: Value,
+    }
+    fn identity() -> Elem { return Elem(0u, identity_value()); }
+    fn combine(a: Elem, b: Elem) -> Elem {
+        return Elem(a.head | b.head, select(combine_values(a.value, b.value), b.value, b.head != 0u));
+    }
+";
+
+/// Shuffles a segmented `Elem` field by field.
+const SEGMENTED_SHUFFLE_UP: &str = "Elem(subgroupShuffleUp(e.head, delta), subgroupShuffleUp(e.value, delta))";
+
+/// Where the segments of a segmented scan start, either as host slices or device buffers of
+/// `u32`s.
+#[derive(Copy, Clone, Debug)]
+pub enum Segments<S> {
+    /// A nonzero flag for the first element of every segment, one per value.
+    HeadFlags(S),
+    /// The sorted start offsets of the segments. Values before the first offset form a
+    /// segment of their own and repeated offsets stand for empty segments.
+    Offsets(S),
+}
+
+impl Engine {
+    /// Scans each segment of `values` independently, see [`Engine::scan`].
+    pub async fn segmented_scan<T: ScanElement>(
+        &self,
+        values: &[T],
+        segments: Segments<&[u32]>,
+        op: ScanOp,
+        kind: ScanKind,
+    ) -> anyhow::Result<Vec<T>> {
+        let segments_slice = match segments {
+            Segments::HeadFlags(flags) => {
+                anyhow::ensure!(
+                    flags.len() == values.len(),
+                    "expected one head flag per value, got {} flags for {} values",
+                    flags.len(),
+                    values.len(),
+                );
+                flags
+            }
+            Segments::Offsets(offsets) => {
+                anyhow::ensure!(offsets.windows(2).all(|w| w[0] <= w[1]), "segment offsets must be sorted");
+                if let Some(&last) = offsets.last() {
+                    anyhow::ensure!(
+                        last as usize <= values.len(),
+                        "segment offset {} is past the end of {} values",
+                        last,
+                        values.len(),
+                    );
+                }
+                offsets
+            }
+        };
+
+        if values.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let values_buf = self
+            .device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("segmented scan values"),
+                contents: bytemuck::cast_slice(values),
+                usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+            });
+
+        // a single segment needs no offsets
+        if segments_slice.is_empty() {
+            self.scan_inner::<T>(&values_buf, op, kind, ScanDirection::Forward)?;
+            return self.map_buffer(&values_buf).await;
+        }
+
+        let segments_buf = self
+            .device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("segmented scan segments"),
+                contents: bytemuck::cast_slice(segments_slice),
+                usage: wgpu::BufferUsages::STORAGE,
+            });
+
+        let segments_buf = match segments {
+            Segments::HeadFlags(_) => Segments::HeadFlags(&segments_buf),
+            Segments::Offsets(_) => Segments::Offsets(&segments_buf),
+        };
+        self.segmented_scan_inner::<T>(&values_buf, segments_buf, op, kind)?;
+
+        self.map_buffer(&values_buf).await
+    }
+
+    /// Scans each segment of a buffer of `T`s in place. The whole buffer is scanned by a single
+    /// chain of dispatches, however many segments there are. Fails if the values, each paired
+    /// with a head flag padded to its size, or the segments exceed
+    /// `max_storage_buffer_binding_size`.
+    pub fn segmented_scan_inner<T: ScanElement>(
+        &self,
+        values: &wgpu::Buffer,
+        segments: Segments<&wgpu::Buffer>,
+        op: ScanOp,
+        kind: ScanKind,
+    ) -> anyhow::Result<()> {
+        let mut encoder = self.device.create_command_encoder(&Default::default());
+        self.record_segmented_scan::<T>(&mut encoder, values, segments, op, kind)?;
+        self.queue.submit(Some(encoder.finish()));
+
+        Ok(())
+    }
+
+    /// Records [`Engine::segmented_scan_inner`] without submitting it.
+    pub(crate) fn record_segmented_scan<T: ScanElement>(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        values: &wgpu::Buffer,
+        segments: Segments<&wgpu::Buffer>,
+        op: ScanOp,
+        kind: ScanKind,
+    ) -> anyhow::Result<()> {
+        let elem_size = std::mem::size_of::<T>() as u64;
+        let len = values.size() / elem_size;
+
+        let (segments_buf, suffix) = match segments {
+            Segments::HeadFlags(flags) => {
+                anyhow::ensure!(
+                    flags.size() == len * 4,
+                    "expected one head flag per value, got {} bytes of flags for {} values",
+                    flags.size(),
+                    len,
+                );
+                (flags, "flags")
+            }
+            Segments::Offsets(offsets) => (offsets, "offsets"),
+        };
+
+        // the head flag is padded to the alignment of the value
+        let pair_size = 2 * elem_size;
+        // pack and unpack bind the values, segments and pairs whole
+        let max_binding_size = self.device.limits().max_storage_buffer_binding_size as u64;
+        anyhow::ensure!(
+            len * pair_size <= max_binding_size && segments_buf.size() <= max_binding_size,
+            "{} bytes of value and head flag pairs or {} bytes of segments exceed the {} byte binding limit",
+            len * pair_size,
+            segments_buf.size(),
+            max_binding_size,
+        );
+
+        let mut decls = scan_decls::<T>(op)? + SEGMENTED_ELEM;
+        if self.capabilities.subgroups {
+            decls += &subgroup_decls(None, SEGMENTED_SHUFFLE_UP);
+        }
+        let specialization = format!("segmented, {}, {:?}", std::any::type_name::<T>(), op);
+
+        let pairs = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("segmented scan pairs"),
+            size: len * pair_size,
+            usage: wgpu::BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+
+        let bufs = [values, segments_buf, &pairs];
+        self.dispatch_segmented_kernel(encoder, &bufs, &specialization, &decls, &format!("pack_{}", suffix), len);
+
+        let elem = ElemDecls {
+            specialization: &specialization,
+            decls: &decls,
+            size: pair_size,
+        };
+        self.scan_levels(encoder, BufferRange::whole(&pairs, pair_size), elem, ScanKind::Inclusive, ScanDirection::Forward);
+
+        let entry_point = match kind {
+            ScanKind::Inclusive => "unpack".to_string(),
+            ScanKind::Exclusive => format!("unpack_exclusive_{}", suffix),
+        };
+        self.dispatch_segmented_kernel(encoder, &bufs, &specialization, &decls, &entry_point, len);
+
+        Ok(())
+    }
+
+    fn dispatch_segmented_kernel(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        bufs: &[&wgpu::Buffer],
+        specialization: &str,
+        decls: &str,
+        entry_point: &str,
+        len: u64,
+    ) {
+        const WG_SIZE: u64 = 256;
+        const MAX_WORKGROUPS: u64 = 65535;
+
+        let pipeline = self.specialized_pipeline(
+            "segmented_scan",
+            specialization,
+            || format!("{}\n{}", decls, SEGMENTED_SCAN),
+            entry_point,
+            &[STORAGE, STORAGE_READ, STORAGE],
+        );
+        let bind_group = self.bind_buffers(&pipeline, bufs);
+
+        let workgroups = len.div_ceil(WG_SIZE);
+        {
+            let mut cpass = encoder.begin_compute_pass(&Default::default());
+            cpass.insert_debug_marker(&format!("segmented_scan {} dispatch", entry_point));
+            cpass.set_pipeline(&pipeline.pipeline);
+            cpass.set_bind_group(0, &bind_group, &[]);
+            cpass.dispatch_workgroups(
+                workgroups.min(MAX_WORKGROUPS) as u32,
+                workgroups.div_ceil(MAX_WORKGROUPS) as u32,
+                1,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::assert_slices_eq;
+
+    use rand::Rng;
+    use rand_xoshiro::{rand_core::SeedableRng, Xoshiro256PlusPlus};
+
+    fn segmented_scan_cpu<T: Copy>(
+        values: &[T],
+        heads: &[bool],
+        kind: ScanKind,
+        identity: T,
+        op: impl Fn(T, T) -> T,
+    ) -> Vec<T> {
+        let mut acc = identity;
+        std::iter::zip(values, heads)
+            .map(|(&x, &head)| {
+                if head {
+                    acc = identity;
+                }
+                let prev = acc;
+                acc = op(acc, x);
+                match kind {
+                    ScanKind::Inclusive => acc,
+                    ScanKind::Exclusive => prev,
+                }
+            })
+            .collect()
+    }
+
+    fn random_heads(rng: &mut Xoshiro256PlusPlus, len: usize, mean_segment_len: u32) -> Vec<bool> {
+        (0..len).map(|_| rng.gen_ratio(1, mean_segment_len)).collect()
+    }
+
+    fn flags(heads: &[bool]) -> Vec<u32> {
+        heads.iter().map(|&head| head as u32).collect()
+    }
+
+    fn offsets(heads: &[bool]) -> Vec<u32> {
+        (0..heads.len() as u32).filter(|&i| heads[i as usize]).collect()
+    }
+
+    #[tokio::test]
+    async fn segmented_sum_works() -> anyhow::Result<()> {
+        let engine = Engine::new().await?;
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(0);
+
+        for (len, mean_segment_len) in [(1, 1), (255, 7), (257, 300), (1 << 11, 1), (100_000, 20), (1 << 20, 1000)] {
+            let values: Vec<u32> = (0..len).map(|_| rng.gen_range(0..100)).collect();
+            let heads = random_heads(&mut rng, len, mean_segment_len);
+
+            for kind in [ScanKind::Inclusive, ScanKind::Exclusive] {
+                println!("{} values, {:?}", len, kind);
+                let expected = segmented_scan_cpu(&values, &heads, kind, 0, |a, b| a + b);
+
+                let flags = flags(&heads);
+                let result = engine.segmented_scan(&values, Segments::HeadFlags(&flags), ScanOp::Add, kind).await?;
+                assert_slices_eq(&result, &expected);
+
+                let offsets = offsets(&heads);
+                let result = engine.segmented_scan(&values, Segments::Offsets(&offsets), ScanOp::Add, kind).await?;
+                assert_slices_eq(&result, &expected);
+            }
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn segmented_scan_ops_work() -> anyhow::Result<()> {
+        let engine = Engine::new().await?;
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(1);
+
+        let heads = random_heads(&mut rng, 100_000, 50);
+        let offsets = offsets(&heads);
+        let segments = Segments::Offsets(&offsets[..]);
+
+        let input: Vec<u32> = (0..heads.len()).map(|_| rng.gen()).collect();
+        let result = engine.segmented_scan(&input, segments, ScanOp::Min, ScanKind::Inclusive).await?;
+        assert_slices_eq(&result, &segmented_scan_cpu(&input, &heads, ScanKind::Inclusive, u32::MAX, u32::min));
+        let result = engine.segmented_scan(&input, segments, ScanOp::Xor, ScanKind::Exclusive).await?;
+        assert_slices_eq(&result, &segmented_scan_cpu(&input, &heads, ScanKind::Exclusive, 0, |a, b| a ^ b));
+
+        let input: Vec<i32> = (0..heads.len()).map(|_| rng.gen_range(-1000..1000)).collect();
+        let result = engine.segmented_scan(&input, segments, ScanOp::Add, ScanKind::Inclusive).await?;
+        assert_slices_eq(&result, &segmented_scan_cpu(&input, &heads, ScanKind::Inclusive, 0, |a, b| a + b));
+
+        let input: Vec<f32> = (0..heads.len()).map(|_| rng.gen_range(-1e6..1e6)).collect();
+        let result = engine.segmented_scan(&input, segments, ScanOp::Max, ScanKind::Exclusive).await?;
+        assert_slices_eq(&result, &segmented_scan_cpu(&input, &heads, ScanKind::Exclusive, f32::NEG_INFINITY, f32::max));
+
+        let input: Vec<u64> = (0..heads.len()).map(|_| rng.gen::<u64>() >> 8).collect();
+        let result = engine.segmented_scan(&input, segments, ScanOp::Add, ScanKind::Inclusive).await?;
+        assert_slices_eq(&result, &segmented_scan_cpu(&input, &heads, ScanKind::Inclusive, 0, |a, b| a + b));
+
+        Ok(())
+    }
+
+    #[test]
+    fn segmented_subgroup_kernels_validate() {
+        let decls = scan_decls::<u64>(ScanOp::Add).unwrap() + SEGMENTED_ELEM + &subgroup_decls(None, SEGMENTED_SHUFFLE_UP);
+        crate::prefix_sum::tests::validate_subgroup_kernels(&decls, None);
+    }
+
+    #[tokio::test]
+    async fn segmented_scan_rejects_invalid_segments() -> anyhow::Result<()> {
+        let engine = Engine::new().await?;
+        let values = [1u32; 10];
+
+        let segments = Segments::HeadFlags(&[1u32; 9][..]);
+        assert!(engine.segmented_scan(&values, segments, ScanOp::Add, ScanKind::Inclusive).await.is_err());
+        let segments = Segments::Offsets(&[0u32, 5, 3][..]);
+        assert!(engine.segmented_scan(&values, segments, ScanOp::Add, ScanKind::Inclusive).await.is_err());
+        let segments = Segments::Offsets(&[0u32, 11][..]);
+        assert!(engine.segmented_scan(&values, segments, ScanOp::Add, ScanKind::Inclusive).await.is_err());
+
+        // a pair of a value and its head flag takes twice the size of the value
+        let max_len = engine.device.limits().max_storage_buffer_binding_size as u64 / 8;
+        for (len, fits) in [(max_len, true), (max_len + 1, false)] {
+            let values = engine.device.create_buffer(&wgpu::BufferDescriptor {
+                label: None,
+                size: 4 * len,
+                usage: wgpu::BufferUsages::STORAGE,
+                mapped_at_creation: false,
+            });
+            let offsets = engine.device.create_buffer(&wgpu::BufferDescriptor {
+                label: None,
+                size: 4,
+                usage: wgpu::BufferUsages::STORAGE,
+                mapped_at_creation: false,
+            });
+            let result = engine.segmented_scan_inner::<u32>(&values, Segments::Offsets(&offsets), ScanOp::Add, ScanKind::Inclusive);
+            assert_eq!(result.is_ok(), fits);
+        }
+        engine.device.poll(wgpu::Maintain::wait()).panic_on_timeout();
+
+        // no offsets and offsets at the very end leave a single segment
+        for offsets in [&[][..], &[10u32][..]] {
+            let result = engine.segmented_scan(&values, Segments::Offsets(offsets), ScanOp::Add, ScanKind::Inclusive).await?;
+            assert_slices_eq(&result, &(1..=10).collect::<Vec<u32>>());
+        }
+
+        Ok(())
+    }
+}